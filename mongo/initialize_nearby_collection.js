@@ -1,3 +1,5 @@
+// The server creates the collection and its indexes at startup (src/database/schema.rs),
+// this script is only kept for manual setups.
 db.createCollection("available");
 
 db.available.createIndex( { "contacts_phone_number_hash" : 1 } );
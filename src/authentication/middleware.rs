@@ -0,0 +1,126 @@
+use crate::authentication::token::TokenSigner;
+use crate::error::NearbyError;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{http::header, Error, FromRequest, HttpMessage, HttpRequest};
+use chrono::Utc;
+use futures::future::{ok, ready, Either, Ready};
+use std::task::{Context, Poll};
+
+/**
+ * The user that sent the request, as proven by its token.
+ * Use it as an handler parameter to require authentication.
+ */
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub phone_number_hash: String,
+    pub device_id: String,
+}
+
+impl AuthenticatedUser {
+    /**
+     * Refuse requests made on behalf of someone else.
+     */
+    pub fn check_is(&self, phone_number_hash: &str) -> Result<(), NearbyError> {
+        if self.phone_number_hash != phone_number_hash {
+            return Err(NearbyError::Forbidden(String::from(
+                "Token doesn't match phone_number_hash",
+            )));
+        }
+        return Ok(());
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = NearbyError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| NearbyError::Unauthorized(String::from("Missing token"))),
+        )
+    }
+}
+
+/**
+ * Verify the `Authorization: Bearer <token>` header of every request.
+ * Requests with an invalid token are refused, valid ones get an
+ * `AuthenticatedUser`. Requests without token go through : handlers that
+ * need authentication refuse them by asking for an `AuthenticatedUser`.
+ */
+pub struct Authentication {
+    signer: TokenSigner,
+}
+
+impl Authentication {
+    pub fn new(signer: TokenSigner) -> Self {
+        Authentication { signer }
+    }
+}
+
+impl<S, B> Transform<S> for Authentication
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticationMiddleware {
+            service,
+            signer: self.signer.clone(),
+        })
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: S,
+    signer: TokenSigner,
+}
+
+impl<S, B> Service for AuthenticationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let token = match bearer_token(&req) {
+            Some(token) => token,
+            None => return Either::Left(self.service.call(req)),
+        };
+        match self.signer.verify(&token, Utc::now()) {
+            Ok(claims) => {
+                req.extensions_mut().insert(AuthenticatedUser {
+                    phone_number_hash: claims.sub,
+                    device_id: claims.device_id,
+                });
+                Either::Left(self.service.call(req))
+            }
+            Err(error) => Either::Right(ok(req.error_response(error))),
+        }
+    }
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    return value.strip_prefix("Bearer ").map(String::from);
+}
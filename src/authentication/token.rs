@@ -0,0 +1,138 @@
+use crate::error::NearbyError;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/**
+ * What a token proves : this device is allowed to act for this phone number hash.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Claims {
+    /// Phone number hash the token is bound to.
+    pub sub: String,
+    pub device_id: String,
+    /// Issued at, and expiration, in seconds since epoch.
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/**
+ * Issue and verify device tokens. A token is `payload.signature`, where payload
+ * is the base64 (url safe) JSON of the `Claims`, and signature its HMAC-SHA256
+ * with the server secret.
+ */
+#[derive(Clone)]
+pub struct TokenSigner {
+    secret: Vec<u8>,
+    validity: Duration,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &[u8], validity: Duration) -> Self {
+        TokenSigner {
+            secret: secret.to_vec(),
+            validity,
+        }
+    }
+
+    pub fn issue(&self, phone_number_hash: &str, device_id: &str, now: DateTime<Utc>) -> String {
+        let claims = Claims {
+            sub: String::from(phone_number_hash),
+            device_id: String::from(device_id),
+            iat: now.timestamp(),
+            exp: (now + self.validity).timestamp(),
+        };
+        // Claims only have strings and integers, this can't fail :
+        let payload = serde_json::to_vec(&claims).expect("Can't serialize claims");
+        let payload = base64::encode_config(payload, base64::URL_SAFE_NO_PAD);
+        let signature = base64::encode_config(self.sign(&payload), base64::URL_SAFE_NO_PAD);
+        return std::format!("{}.{}", payload, signature);
+    }
+
+    pub fn verify(&self, token: &str, now: DateTime<Utc>) -> Result<Claims, NearbyError> {
+        let invalid = || NearbyError::Unauthorized(String::from("Invalid token"));
+        let mut parts = token.splitn(2, '.');
+        let payload = parts.next().ok_or_else(invalid)?;
+        let signature = parts.next().ok_or_else(invalid)?;
+        let signature =
+            base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify(&signature).map_err(|_| invalid())?;
+
+        let payload =
+            base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        let claims: Claims = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+        if claims.exp <= now.timestamp() {
+            return Err(NearbyError::Unauthorized(String::from("Token expired")));
+        }
+        return Ok(claims);
+    }
+
+    pub fn validity(&self) -> Duration {
+        self.validity
+    }
+
+    fn sign(&self, payload: &str) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        return mac.finalize().into_bytes().to_vec();
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        // HMAC accepts keys of any size :
+        HmacSha256::new_varkey(&self.secret).expect("Invalid HMAC key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> TokenSigner {
+        TokenSigner::new(b"not so secret", Duration::hours(1))
+    }
+
+    #[test]
+    fn test_issued_tokens_are_valid() {
+        let now = Utc::now();
+        let token = signer().issue("Peppa", "peppa-phone", now);
+        let claims = signer().verify(&token, now).expect("Token should be valid");
+        assert_eq!(claims.sub, "Peppa");
+        assert_eq!(claims.device_id, "peppa-phone");
+    }
+
+    #[test]
+    fn test_expired_tokens_are_refused() {
+        let now = Utc::now();
+        let token = signer().issue("Peppa", "peppa-phone", now);
+        assert!(signer().verify(&token, now + Duration::hours(2)).is_err());
+    }
+
+    #[test]
+    fn test_tampered_tokens_are_refused() {
+        let now = Utc::now();
+        let token = signer().issue("Peppa", "peppa-phone", now);
+        let signature = token.split('.').nth(1).expect("No signature");
+        let forged_claims = Claims {
+            sub: String::from("Rebecca"),
+            device_id: String::from("peppa-phone"),
+            iat: now.timestamp(),
+            exp: (now + Duration::hours(1)).timestamp(),
+        };
+        let forged_payload = base64::encode_config(
+            serde_json::to_vec(&forged_claims).expect("Can't serialize"),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let forged = std::format!("{}.{}", forged_payload, signature);
+        assert!(signer().verify(&forged, now).is_err());
+
+        let other_signer = TokenSigner::new(b"another secret", Duration::hours(1));
+        assert!(other_signer.verify(&token, now).is_err());
+        assert!(signer().verify("not a token", now).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+
+/**
+ * Where stores get the time from, to know which users are expired. Tests use a
+ * `FixedClock`, so they don't depend on the wall time.
+ */
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        return Arc::new(SystemClock);
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        return Utc::now();
+    }
+}
+
+/**
+ * Always gives the same time, unless tests move it.
+ */
+#[cfg(test)]
+pub struct FixedClock {
+    now: std::sync::RwLock<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn at(rfc3339: &str) -> Arc<FixedClock> {
+        let now = DateTime::parse_from_rfc3339(rfc3339).expect("Can't parse date");
+        return Arc::new(FixedClock {
+            now: std::sync::RwLock::new(DateTime::from(now)),
+        });
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        let mut now = self.now.write().expect("Clock is poisoned");
+        *now = *now + duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        return *self.now.read().expect("Clock is poisoned");
+    }
+}
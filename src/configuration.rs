@@ -0,0 +1,651 @@
+use serde::Deserialize;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+
+/**
+ * Path of the configuration file used when NEARBY_CONFIG is not set.
+ * This file is optional, if it doesn't exist we use default values.
+ */
+const DEFAULT_CONFIGURATION_PATH: &str = "nearby.toml";
+
+#[derive(Debug)]
+pub struct ConfigurationError {
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Mongo,
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mongo" => Ok(StorageBackend::Mongo),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(std::format!("unknown storage backend \"{}\"", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfiguration {
+    pub bind_address: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfiguration {
+    pub storage: StorageBackend,
+    pub mongo_uri: String,
+    pub database_name: String,
+    pub collection_name: String,
+    /// Follow the collection with a change stream instead of publishing our own
+    /// writes, so events from all instances are seen. Needs a replica set.
+    pub change_streams: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CleanerConfiguration {
+    /// MongoDB removes expired users with a TTL index, the cleaner is only a
+    /// fallback. It always runs with the memory storage.
+    pub enabled: bool,
+    pub interval_s: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SearchConfiguration {
+    pub default_radius_m: f32,
+    pub min_radius_m: f32,
+    pub max_radius_m: f32,
+    /// When set, clients can't ask for more results than that.
+    pub max_limit: Option<u32>,
+    /// How far from its own declared location a user can search from.
+    pub max_distance_from_declared_m: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfiguration {
+    /// Nearby searches and posted availabilities a phone number hash, or an IP
+    /// address, can send at once.
+    pub nearby_burst: u32,
+    /// Then how many it can send per minute.
+    pub nearby_per_minute: u32,
+    /// Verification codes a phone number, or an IP address, can ask at once.
+    pub verification_code_burst: u32,
+    pub verification_code_per_minute: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfiguration {
+    /// How far in the future `available_until` can be.
+    pub max_availability_hours: i64,
+    pub max_contacts: usize,
+    pub max_hash_length: usize,
+    /// In characters.
+    pub max_status_message_length: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AuthenticationConfiguration {
+    /// Secret used to sign device tokens. When empty, a random one is generated
+    /// at startup, so tokens don't survive a restart.
+    pub secret: String,
+    pub token_validity_hours: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct VerificationConfiguration {
+    pub code_validity_minutes: i64,
+    /// Wrong codes a phone number can send during a window, whatever the
+    /// number of codes it asked.
+    pub max_attempts: u32,
+    /// Codes sent to a phone number during a window.
+    pub max_codes: u32,
+    pub window_minutes: i64,
+    /// SMS are written to this file instead of being sent. When empty, they are printed.
+    pub sms_log_path: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HashKeyConfiguration {
+    pub version: u32,
+    pub secret: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct HashingConfiguration {
+    /// Version of the key used to store users, the other keys are only used for contacts.
+    pub current_version: u32,
+    /// When empty, a random key is generated at startup, so matches don't survive a restart.
+    pub keys: Vec<HashKeyConfiguration>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PrivacyConfiguration {
+    /// Size of the grid approximate locations are snapped to, 0 to disable it.
+    pub grid_size_m: f64,
+    /// Upper bounds of the distance buckets, in increasing order.
+    pub distance_buckets_m: Vec<f32>,
+}
+
+/**
+ * Everything that can change between two deployments.
+ * Values come from a TOML file, and can be overriden by environment variables
+ * (see `apply_overrides` for the list).
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Configuration {
+    pub server: ServerConfiguration,
+    pub database: DatabaseConfiguration,
+    pub cleaner: CleanerConfiguration,
+    pub search: SearchConfiguration,
+    pub validation: ValidationConfiguration,
+    pub authentication: AuthenticationConfiguration,
+    pub verification: VerificationConfiguration,
+    pub hashing: HashingConfiguration,
+    pub privacy: PrivacyConfiguration,
+    pub rate_limit: RateLimitConfiguration,
+}
+
+impl Default for ServerConfiguration {
+    fn default() -> Self {
+        ServerConfiguration {
+            bind_address: String::from("127.0.0.1:8080"),
+        }
+    }
+}
+
+impl Default for DatabaseConfiguration {
+    fn default() -> Self {
+        DatabaseConfiguration {
+            storage: StorageBackend::Mongo,
+            mongo_uri: String::from("mongodb://localhost:27017/"),
+            database_name: String::from("nearby"),
+            collection_name: String::from("available"),
+            change_streams: false,
+        }
+    }
+}
+
+impl Default for CleanerConfiguration {
+    fn default() -> Self {
+        CleanerConfiguration {
+            enabled: false,
+            interval_s: 300,
+        }
+    }
+}
+
+impl Default for SearchConfiguration {
+    fn default() -> Self {
+        SearchConfiguration {
+            default_radius_m: 10_000f32,
+            min_radius_m: 100f32,
+            max_radius_m: 50_000f32,
+            max_limit: None,
+            // Approximate locations are snapped to a grid, leave some room :
+            max_distance_from_declared_m: 2_000.0,
+        }
+    }
+}
+
+impl Default for ValidationConfiguration {
+    fn default() -> Self {
+        ValidationConfiguration {
+            max_availability_hours: 24,
+            max_contacts: 2000,
+            max_hash_length: 128,
+            max_status_message_length: 140,
+        }
+    }
+}
+
+impl Default for AuthenticationConfiguration {
+    fn default() -> Self {
+        AuthenticationConfiguration {
+            secret: String::new(),
+            token_validity_hours: 24 * 30,
+        }
+    }
+}
+
+impl Default for VerificationConfiguration {
+    fn default() -> Self {
+        VerificationConfiguration {
+            code_validity_minutes: 10,
+            max_attempts: 5,
+            max_codes: 3,
+            window_minutes: 60,
+            sms_log_path: String::new(),
+        }
+    }
+}
+
+impl Default for HashingConfiguration {
+    fn default() -> Self {
+        HashingConfiguration {
+            current_version: 1,
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl Default for PrivacyConfiguration {
+    fn default() -> Self {
+        PrivacyConfiguration {
+            grid_size_m: 500.0,
+            distance_buckets_m: vec![500.0, 1_000.0, 2_000.0, 5_000.0, 10_000.0, 20_000.0],
+        }
+    }
+}
+
+impl Default for RateLimitConfiguration {
+    fn default() -> Self {
+        RateLimitConfiguration {
+            nearby_burst: 10,
+            nearby_per_minute: 6,
+            verification_code_burst: 3,
+            verification_code_per_minute: 1,
+        }
+    }
+}
+
+impl FromStr for HashKeyConfiguration {
+    type Err = String;
+    /// Parse "version:secret".
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.splitn(2, ':');
+        let version = parts.next().unwrap_or_default();
+        let secret = parts
+            .next()
+            .ok_or_else(|| String::from("hashing keys must look like \"version:secret\""))?;
+        return Ok(HashKeyConfiguration {
+            version: version
+                .parse()
+                .map_err(|_| std::format!("invalid hashing key version \"{}\"", version))?,
+            secret: String::from(secret),
+        });
+    }
+}
+
+impl SearchConfiguration {
+    /**
+     * Return the radius to use for a nearby search, or a message explaining why
+     * the requested one is refused.
+     */
+    pub fn radius_m(&self, requested: Option<f32>) -> Result<f32, String> {
+        let radius = match requested {
+            None => return Ok(self.default_radius_m),
+            Some(radius) => radius,
+        };
+        if !radius.is_finite() || radius < self.min_radius_m || radius > self.max_radius_m {
+            return Err(std::format!(
+                "max_distance_m must be between {} and {} meters",
+                self.min_radius_m,
+                self.max_radius_m
+            ));
+        }
+        return Ok(radius);
+    }
+
+    /**
+     * Return the maximum number of results for a nearby search, `None` meaning
+     * no limit.
+     */
+    pub fn limit(&self, requested: Option<u32>) -> Result<Option<u32>, String> {
+        match (requested, self.max_limit) {
+            (Some(0), _) => Err(String::from("limit must be greater than 0")),
+            (Some(limit), Some(max_limit)) if limit > max_limit => {
+                Err(std::format!("limit must not be greater than {}", max_limit))
+            }
+            (None, max_limit) => Ok(max_limit),
+            (Some(limit), _) => Ok(Some(limit)),
+        }
+    }
+}
+
+impl Configuration {
+    /**
+     * Load the configuration file given by NEARBY_CONFIG (or ./nearby.toml),
+     * then apply environment overrides.
+     */
+    pub fn load() -> Result<Configuration, ConfigurationError> {
+        let mut configuration = match std::env::var("NEARBY_CONFIG") {
+            Ok(path) => Configuration::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIGURATION_PATH).exists() => {
+                Configuration::from_file(Path::new(DEFAULT_CONFIGURATION_PATH))?
+            }
+            Err(_) => Configuration::default(),
+        };
+        configuration.apply_overrides(|name| std::env::var(name).ok())?;
+        configuration.check()?;
+        return Ok(configuration);
+    }
+
+    /**
+     * Refuse values that can't work, once the file and the overrides are read.
+     */
+    pub fn check(&self) -> Result<(), ConfigurationError> {
+        if self.verification.max_attempts == 0 {
+            return Err(ConfigurationError {
+                message: String::from("verification.max_attempts must be greater than 0"),
+            });
+        }
+        // Intervals of 0 make the timer panic :
+        if self.cleaner.interval_s == 0 {
+            return Err(ConfigurationError {
+                message: String::from("cleaner.interval_s must be greater than 0"),
+            });
+        }
+        let search = &self.search;
+        if !(search.min_radius_m <= search.default_radius_m
+            && search.default_radius_m <= search.max_radius_m)
+        {
+            return Err(ConfigurationError {
+                message: String::from(
+                    "search.default_radius_m must be between search.min_radius_m and search.max_radius_m",
+                ),
+            });
+        }
+        let buckets = &self.privacy.distance_buckets_m;
+        if buckets.is_empty() || !buckets.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(ConfigurationError {
+                message: String::from(
+                    "privacy.distance_buckets_m must not be empty, and must be in increasing order",
+                ),
+            });
+        }
+        return Ok(());
+    }
+
+    pub fn from_file(path: &Path) -> Result<Configuration, ConfigurationError> {
+        let content = std::fs::read_to_string(path).map_err(|err| ConfigurationError {
+            message: std::format!("Can't read {} : {}", path.display(), err),
+        })?;
+        return Configuration::from_toml(&content);
+    }
+
+    pub fn from_toml(content: &str) -> Result<Configuration, ConfigurationError> {
+        return toml::from_str(content).map_err(|err| ConfigurationError {
+            message: std::format!("Invalid configuration : {}", err),
+        });
+    }
+
+    /**
+     * `lookup` returns the value of an environment variable, it is a parameter
+     * so tests don't have to modify the process environment.
+     */
+    pub fn apply_overrides<F>(&mut self, lookup: F) -> Result<(), ConfigurationError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(value) = lookup("NEARBY_BIND_ADDRESS") {
+            self.server.bind_address = value;
+        }
+        if let Some(value) = lookup("NEARBY_STORAGE") {
+            self.database.storage = parse_override("NEARBY_STORAGE", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_MONGO_URI") {
+            self.database.mongo_uri = value;
+        }
+        if let Some(value) = lookup("NEARBY_DATABASE_NAME") {
+            self.database.database_name = value;
+        }
+        if let Some(value) = lookup("NEARBY_COLLECTION_NAME") {
+            self.database.collection_name = value;
+        }
+        if let Some(value) = lookup("NEARBY_CHANGE_STREAMS") {
+            self.database.change_streams = parse_override("NEARBY_CHANGE_STREAMS", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_CLEANER_ENABLED") {
+            self.cleaner.enabled = parse_override("NEARBY_CLEANER_ENABLED", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_CLEANER_INTERVAL_S") {
+            self.cleaner.interval_s = parse_override("NEARBY_CLEANER_INTERVAL_S", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_DEFAULT_RADIUS_M") {
+            self.search.default_radius_m = parse_override("NEARBY_DEFAULT_RADIUS_M", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_MIN_RADIUS_M") {
+            self.search.min_radius_m = parse_override("NEARBY_MIN_RADIUS_M", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_MAX_RADIUS_M") {
+            self.search.max_radius_m = parse_override("NEARBY_MAX_RADIUS_M", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_MAX_LIMIT") {
+            self.search.max_limit = Some(parse_override("NEARBY_MAX_LIMIT", &value)?);
+        }
+        if let Some(value) = lookup("NEARBY_MAX_AVAILABILITY_HOURS") {
+            self.validation.max_availability_hours =
+                parse_override("NEARBY_MAX_AVAILABILITY_HOURS", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_MAX_CONTACTS") {
+            self.validation.max_contacts = parse_override("NEARBY_MAX_CONTACTS", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_AUTH_SECRET") {
+            self.authentication.secret = value;
+        }
+        if let Some(value) = lookup("NEARBY_SMS_LOG_PATH") {
+            self.verification.sms_log_path = value;
+        }
+        if let Some(value) = lookup("NEARBY_TOKEN_VALIDITY_HOURS") {
+            self.authentication.token_validity_hours =
+                parse_override("NEARBY_TOKEN_VALIDITY_HOURS", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_HASH_KEYS") {
+            // Comma separated "version:secret" list :
+            self.hashing.keys = value
+                .split(',')
+                .map(|key| parse_override("NEARBY_HASH_KEYS", key))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(value) = lookup("NEARBY_HASH_CURRENT_VERSION") {
+            self.hashing.current_version = parse_override("NEARBY_HASH_CURRENT_VERSION", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_GRID_SIZE_M") {
+            self.privacy.grid_size_m = parse_override("NEARBY_GRID_SIZE_M", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_MAX_DISTANCE_FROM_DECLARED_M") {
+            self.search.max_distance_from_declared_m =
+                parse_override("NEARBY_MAX_DISTANCE_FROM_DECLARED_M", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_RATE_LIMIT_BURST") {
+            self.rate_limit.nearby_burst = parse_override("NEARBY_RATE_LIMIT_BURST", &value)?;
+        }
+        if let Some(value) = lookup("NEARBY_RATE_LIMIT_PER_MINUTE") {
+            self.rate_limit.nearby_per_minute =
+                parse_override("NEARBY_RATE_LIMIT_PER_MINUTE", &value)?;
+        }
+        return Ok(());
+    }
+}
+
+fn parse_override<T>(name: &str, value: &str) -> Result<T, ConfigurationError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    return value.parse::<T>().map_err(|err| ConfigurationError {
+        message: std::format!("Invalid value for {} : {}", name, err),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_missing_values_use_defaults() {
+        let configuration = Configuration::from_toml(
+            r#"
+            [database]
+            database_name = "nearby_staging"
+            "#,
+        )
+        .expect("Can't parse configuration");
+        assert_eq!(configuration.database.database_name, "nearby_staging");
+        assert_eq!(configuration.database.collection_name, "available");
+        assert_eq!(configuration.server.bind_address, "127.0.0.1:8080");
+        assert_eq!(configuration.cleaner.interval_s, 300);
+    }
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let mut configuration = Configuration::from_toml(
+            r#"
+            [server]
+            bind_address = "0.0.0.0:80"
+            [search]
+            default_radius_m = 2000.0
+            "#,
+        )
+        .expect("Can't parse configuration");
+        let environment: HashMap<&str, &str> = [
+            ("NEARBY_STORAGE", "memory"),
+            ("NEARBY_MONGO_URI", "mongodb://mongo.prod:27017/"),
+            ("NEARBY_CLEANER_INTERVAL_S", "60"),
+            ("NEARBY_CHANGE_STREAMS", "true"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        configuration
+            .apply_overrides(|name| environment.get(name).map(|value| value.to_string()))
+            .expect("Can't apply overrides");
+
+        assert_eq!(configuration.server.bind_address, "0.0.0.0:80");
+        assert_eq!(configuration.database.storage, StorageBackend::Memory);
+        assert_eq!(
+            configuration.database.mongo_uri,
+            "mongodb://mongo.prod:27017/"
+        );
+        assert_eq!(configuration.cleaner.interval_s, 60);
+        assert!(configuration.database.change_streams);
+        assert!((configuration.search.default_radius_m - 2000.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_search_radius_is_bounded() {
+        let search = SearchConfiguration::default();
+        assert_eq!(search.radius_m(None), Ok(10_000f32));
+        assert_eq!(search.radius_m(Some(2_000f32)), Ok(2_000f32));
+        assert!(search.radius_m(Some(10f32)).is_err());
+        assert!(search.radius_m(Some(1_000_000f32)).is_err());
+        assert!(search.radius_m(Some(f32::NAN)).is_err());
+    }
+
+    #[test]
+    fn test_search_limit_is_bounded() {
+        let mut search = SearchConfiguration::default();
+        assert_eq!(search.limit(None), Ok(None));
+        assert_eq!(search.limit(Some(500)), Ok(Some(500)));
+        assert!(search.limit(Some(0)).is_err());
+
+        search.max_limit = Some(20);
+        assert_eq!(search.limit(None), Ok(Some(20)));
+        assert_eq!(search.limit(Some(5)), Ok(Some(5)));
+        assert!(search.limit(Some(21)).is_err());
+    }
+
+    #[test]
+    fn test_hashing_keys_can_be_overriden() {
+        let mut configuration = Configuration::from_toml(
+            r#"
+            [hashing]
+            current_version = 1
+            [[hashing.keys]]
+            version = 1
+            secret = "old secret"
+            "#,
+        )
+        .expect("Can't parse configuration");
+        assert_eq!(configuration.hashing.keys.len(), 1);
+
+        configuration
+            .apply_overrides(|name| match name {
+                "NEARBY_HASH_KEYS" => Some(String::from("1:old secret,2:new:secret")),
+                "NEARBY_HASH_CURRENT_VERSION" => Some(String::from("2")),
+                _ => None,
+            })
+            .expect("Can't apply overrides");
+        assert_eq!(configuration.hashing.current_version, 2);
+        assert_eq!(configuration.hashing.keys[1].version, 2);
+        assert_eq!(configuration.hashing.keys[1].secret, "new:secret");
+
+        let res = configuration.apply_overrides(|name| match name {
+            "NEARBY_HASH_KEYS" => Some(String::from("no version")),
+            _ => None,
+        });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_invalid_override_is_an_error() {
+        let mut configuration = Configuration::default();
+        let res = configuration.apply_overrides(|name| match name {
+            "NEARBY_CLEANER_INTERVAL_S" => Some(String::from("every minute")),
+            _ => None,
+        });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_verification_codes_need_attempts() {
+        assert!(Configuration::default().check().is_ok());
+        let configuration = Configuration::from_toml("[verification]\nmax_attempts = 0")
+            .expect("Can't parse configuration");
+        assert!(configuration.check().is_err());
+    }
+
+    #[test]
+    fn test_cleaner_needs_an_interval() {
+        let mut configuration = Configuration::default();
+        configuration
+            .apply_overrides(|name| match name {
+                "NEARBY_CLEANER_INTERVAL_S" => Some(String::from("0")),
+                _ => None,
+            })
+            .expect("Can't apply overrides");
+        assert!(configuration.check().is_err());
+    }
+
+    #[test]
+    fn test_default_radius_is_within_bounds() {
+        let configuration = Configuration::from_toml(
+            "[search]\ndefault_radius_m = 60000.0\nmax_radius_m = 50000.0",
+        )
+        .expect("Can't parse configuration");
+        assert!(configuration.check().is_err());
+        let configuration =
+            Configuration::from_toml("[search]\ndefault_radius_m = 50.0\nmin_radius_m = 100.0")
+                .expect("Can't parse configuration");
+        assert!(configuration.check().is_err());
+    }
+
+    #[test]
+    fn test_distance_buckets_are_sorted() {
+        let configuration = Configuration::from_toml("[privacy]\ndistance_buckets_m = []")
+            .expect("Can't parse configuration");
+        assert!(configuration.check().is_err());
+        let configuration =
+            Configuration::from_toml("[privacy]\ndistance_buckets_m = [1000.0, 500.0]")
+                .expect("Can't parse configuration");
+        assert!(configuration.check().is_err());
+    }
+}
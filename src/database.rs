@@ -1,2 +1,7 @@
+pub mod availability_store;
+pub mod available_users_cleaner;
 pub mod database_interface;
-pub mod available_users_cleaner;
\ No newline at end of file
+pub mod documents;
+pub mod in_memory_store;
+pub mod migrations;
+pub mod schema;
@@ -0,0 +1,64 @@
+use crate::database::database_interface::ReplacedOrInserted;
+use crate::error::NearbyError;
+use crate::models::user;
+use chrono::{DateTime, FixedOffset};
+
+/**
+ * Everything the routes need from a storage backend.
+ * `DataBaseInterface` is the MongoDB implementation, other backends only have to
+ * provide the same semantic (see `create_nearby_stage` for the nearby query).
+ */
+pub trait AvailabilityStore: Clone + Send + Unpin + 'static {
+    /**
+     * Insert the user in available users, or replace its previous status.
+     */
+    async fn set_user_available(
+        &self,
+        user: &user::User,
+    ) -> Result<ReplacedOrInserted, NearbyError>;
+
+    /**
+     * Return the user as stored by `set_user_available`, or None if it is not available.
+     */
+    async fn get_user(&self, phone_hash: &str) -> Result<Option<user::User>, NearbyError>;
+
+    /**
+     * Return available users that have `my_phone_hash` in their contacts and that
+     * are less than `max_distance_m` meters away, sorted by distance.
+     * At most `limit` users are returned, all of them if it is `None`.
+     * In `mutual` mode, users must also be in the contacts of `my_phone_hash`
+     * (as stored by `set_user_available`), so nobody discovers people who didn't
+     * pick them.
+     * Latitude and longitude are in decimal degrees on a WGS84 ellipsoid.
+     */
+    async fn get_contacts_available_nearby(
+        &self,
+        my_phone_hash: &str,
+        my_latitude: f64,
+        my_longitude: f64,
+        max_distance_m: f32,
+        limit: Option<u32>,
+        mutual: bool,
+    ) -> Result<Vec<user::LocalizedUser>, NearbyError>;
+
+    /**
+     * Remove a user from available users right now, whatever its `available_until`.
+     * Return false if this user was not available.
+     */
+    async fn remove_user(&self, phone_hash: &str) -> Result<bool, NearbyError>;
+
+    /**
+     * Remove all user that are no longuer available at `date_time`.
+     * Return the phone number hashes of the users removed.
+     */
+    async fn remove_available_until(
+        &self,
+        date_time: DateTime<FixedOffset>,
+    ) -> Result<Vec<String>, NearbyError>;
+
+    /**
+     * Usefull for testing, will return the number of deleted items.
+     */
+    #[cfg(test)]
+    async fn clear_database(&self) -> Result<i64, NearbyError>;
+}
@@ -1,16 +1,17 @@
+use crate::database::availability_store::AvailabilityStore;
 use actix::prelude::*;
-use crate::database::database_interface::DataBaseInterface;
-use core::time::Duration;
 use chrono::{DateTime, FixedOffset, Utc};
+use core::time::Duration;
 
-pub struct AvailableUserCleaner {
-    database_interface: DataBaseInterface,
+pub struct AvailableUserCleaner<S: AvailabilityStore> {
+    database_interface: S,
+    interval: Duration,
 }
-impl Actor for AvailableUserCleaner {
+impl<S: AvailabilityStore> Actor for AvailableUserCleaner<S> {
     type Context = Context<Self>;
     fn started(&mut self, ctx: &mut Context<Self>) {
         println!("Starting User Cleaner");
-        ctx.run_interval(Duration::from_secs(300), move |this, _| {
+        ctx.run_interval(self.interval, move |this, _| {
             Arbiter::spawn(AvailableUserCleaner::clear_no_longuer_available_users(
                 this.database_interface.clone(),
             ));
@@ -18,23 +19,24 @@ impl Actor for AvailableUserCleaner {
     }
 }
 
-impl AvailableUserCleaner {
-    pub fn new(database_interface: DataBaseInterface) -> Self {
+impl<S: AvailabilityStore> AvailableUserCleaner<S> {
+    pub fn new(database_interface: S, interval: Duration) -> Self {
         AvailableUserCleaner {
-            database_interface: database_interface,
+            database_interface,
+            interval,
         }
     }
-    pub async fn clear_no_longuer_available_users(database_interface: DataBaseInterface) {
+    pub async fn clear_no_longuer_available_users(database_interface: S) {
         println!("Clearing database for availaible users");
         let now: DateTime<FixedOffset> = DateTime::from(Utc::now());
         let clear_res = database_interface.remove_available_until(now).await;
         match clear_res {
-            Ok(n) => {
-                println!("Removed {} users", n)
+            Ok(removed) => {
+                println!("Removed {} users", removed.len())
             }
             Err(err) => {
-                println!("Error when clearing database : {}", err.message);
+                println!("Error when clearing database : {}", err);
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -1,97 +1,228 @@
+use crate::clock::{SharedClock, SystemClock};
+use crate::configuration::DatabaseConfiguration;
+use crate::database::documents::{self, StoredContact, StoredUser, TypedCollection};
+use crate::database::{availability_store::AvailabilityStore, schema};
+use crate::error::NearbyError;
 use crate::models::user;
-use actix_web::web::Data;
 use chrono::{DateTime, FixedOffset, Utc};
 use futures::StreamExt;
-use mongodb::{bson, bson::bson, bson::doc, Client, Collection};
+use mongodb::{
+    bson,
+    bson::bson,
+    bson::doc,
+    options::{FindOneAndReplaceOptions, FindOneOptions, FindOptions, ReturnDocument},
+    Client, Collection, Database,
+};
+use std::collections::HashSet;
 
-#[derive(Debug)]
-pub struct DatabaseError {
-    pub message: String,
+#[derive(Clone)]
+pub struct DataBaseInterface {
+    database: Database,
+    available_users: TypedCollection<StoredUser>,
+    /// Users available until before now are hidden.
+    clock: SharedClock,
 }
 
-impl From<mongodb::error::Error> for DatabaseError {
-    fn from(db_error: mongodb::error::Error) -> Self {
-        return DatabaseError {
-            message: std::format!("Database Error : {}", db_error),
-        };
-    }
+pub enum ReplacedOrInserted {
+    /// The user was already available, with what changed since.
+    Replaced(UserChanges),
+    Inserted,
+}
+
+/**
+ * What changed between two statuses of an available user.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UserChanges {
+    /// Coordinates, or their precision.
+    pub location: bool,
+    /// `available_until`.
+    pub window: bool,
+    /// The picked contacts, whatever their order.
+    pub contacts: bool,
+    /// What contacts see, like the status message.
+    pub status: bool,
 }
 
-impl From<bson::de::Error> for DatabaseError {
-    fn from(bson_error: bson::de::Error) -> Self {
-        return DatabaseError {
-            message: std::format!("BSON Error : {}", bson_error),
+impl UserChanges {
+    pub fn between(before: &user::User, after: &user::User) -> Self {
+        let contacts_of = |user: &user::User| -> HashSet<String> {
+            user.contacts_phone_number_hash.iter().cloned().collect()
+        };
+        return UserChanges {
+            location: before.latitude != after.latitude
+                || before.longitude != after.longitude
+                || before.location_precision != after.location_precision,
+            window: before.available_until != after.available_until,
+            contacts: contacts_of(before) != contacts_of(after),
+            status: before.status_message != after.status_message,
         };
     }
-}
-// TODO : Use that : https://developer.mongodb.com/article/serde-improvements/
-#[derive(Clone)]
-pub struct DataBaseInterface {
-    client: Client,
-    available_collection: Collection,
-}
 
-pub enum ReplacedOrInserted {
-    Replaced,
-    Inserted,
+    pub fn any(&self) -> bool {
+        return self.location || self.window || self.contacts || self.status;
+    }
 }
 
 impl DataBaseInterface {
-    pub async fn new() -> Result<DataBaseInterface, DatabaseError> {
-        let client = Client::with_uri_str("mongodb://localhost:27017/").await?;
-        let collection = client.database("nearby").collection("available");
+    pub async fn new(
+        configuration: &DatabaseConfiguration,
+    ) -> Result<DataBaseInterface, NearbyError> {
+        let client = Client::with_uri_str(&configuration.mongo_uri).await?;
+        let database = client.database(&configuration.database_name);
+        schema::ensure_schema(&database, &configuration.collection_name).await?;
+        let collection = database.collection(&configuration.collection_name);
         return Ok(DataBaseInterface {
-            client: client.clone(),
-            available_collection: collection.clone(),
+            database,
+            available_users: TypedCollection::new(collection),
+            clock: SystemClock::shared(),
         });
     }
-    pub async fn set_user_available(
-        self: &DataBaseInterface,
-        user: &user::User,
-    ) -> Result<ReplacedOrInserted, DatabaseError> {
-        // We start by looking for this user in the available users :
-        let filter = doc! {"phone_number_hash": user.phone_number_hash.clone()};
-        let replaced = self
-            .available_collection
-            .find_one_and_replace(filter, user.to_bson_document(), None)
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        return self;
+    }
+
+    /**
+     * For migrations, and what is shared by instances apart from users.
+     */
+    pub fn database(&self) -> Database {
+        return self.database.clone();
+    }
+
+    /**
+     * The available users, for the change stream watcher and migrations.
+     */
+    pub fn available_collection(&self) -> Collection {
+        return self.available_users.untyped().clone();
+    }
+
+    /**
+     * Return the contacts of an available user, or None if this user is not available.
+     */
+    async fn find_contacts_of(&self, phone_hash: &str) -> Result<Option<Vec<String>>, NearbyError> {
+        let options = FindOneOptions::builder()
+            .projection(doc! {"contacts_phone_number_hash": 1})
+            .build();
+        let document = self
+            .available_users
+            .untyped()
+            .find_one(
+                doc! {"phone_number_hash": phone_hash, "available_until": not_expired(self.clock.now())},
+                options,
+            )
             .await?;
+        return Ok(document.map(|document| {
+            document
+                .get_array("contacts_phone_number_hash")
+                .map(|contacts| {
+                    contacts
+                        .iter()
+                        .filter_map(|contact| contact.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default()
+        }));
+    }
+}
 
-        if let Some(_) = replaced {
-            // Ok we found the user and we replace its status, we can return
-            return Ok(ReplacedOrInserted::Replaced);
+impl AvailabilityStore for DataBaseInterface {
+    async fn set_user_available(
+        &self,
+        user: &user::User,
+    ) -> Result<ReplacedOrInserted, NearbyError> {
+        // A single write, so concurrent posts can't insert the user twice :
+        let filter = doc! {"phone_number_hash": user.phone_number_hash.clone()};
+        let options = FindOneAndReplaceOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::Before)
+            .build();
+        let stored = StoredUser {
+            updated_at: Some(self.clock.now().into()),
+            ..StoredUser::from(user)
+        };
+        let mut res = self
+            .available_users
+            .find_one_and_replace(filter.clone(), &stored, options.clone())
+            .await;
+        if let Err(NearbyError::Conflict(_)) = res {
+            // Another upsert inserted it first (unique index), it is there now :
+            res = self
+                .available_users
+                .find_one_and_replace(filter, &stored, options)
+                .await;
         }
 
-        // Else, we must insert a new user available into the collection :
-        let _ = self
-            .available_collection
-            .insert_one(user.to_bson_document(), None)
-            .await?;
+        return match res? {
+            Some(before) => Ok(ReplacedOrInserted::Replaced(UserChanges::between(
+                &user::User::from(before),
+                user,
+            ))),
+            None => Ok(ReplacedOrInserted::Inserted),
+        };
+    }
 
-        return Ok(ReplacedOrInserted::Inserted);
+    async fn get_user(&self, phone_hash: &str) -> Result<Option<user::User>, NearbyError> {
+        let stored = self
+            .available_users
+            .find_one(
+                doc! {"phone_number_hash": phone_hash, "available_until": not_expired(self.clock.now())},
+                None,
+            )
+            .await?;
+        return Ok(stored.map(user::User::from));
     }
 
     /**
      * Here latitude and longitde are in decimal degrees on a WGS84 ellipsoid
      * (because Mongo do the job !).
      */
-    pub async fn get_contacts_available_nearby(
-        self: &DataBaseInterface,
-        my_phone_hash: &String,
+    async fn get_contacts_available_nearby(
+        &self,
+        my_phone_hash: &str,
         my_latitude: f64,
         my_longitude: f64,
         max_distance_m: f32,
-    ) -> Result<Vec<user::LocalizedUser>, DatabaseError> {
-        let pipeline = vec![
-            create_nearby_stage(my_phone_hash, my_latitude, my_longitude, max_distance_m),
+        limit: Option<u32>,
+        mutual: bool,
+    ) -> Result<Vec<user::LocalizedUser>, NearbyError> {
+        let my_contacts = if mutual {
+            match self.find_contacts_of(my_phone_hash).await? {
+                Some(contacts) => Some(contacts),
+                // We are not available, so we have no contacts to match with :
+                None => return Ok(Vec::new()),
+            }
+        } else {
+            None
+        };
+        let mut pipeline = vec![
+            create_nearby_stage(
+                self.clock.now(),
+                my_phone_hash,
+                my_latitude,
+                my_longitude,
+                max_distance_m,
+                my_contacts.as_deref(),
+            ),
             create_projection_stage(),
         ];
-        let mut cursor = self.available_collection.aggregate(pipeline, None).await?;
+        if let Some(limit) = limit {
+            // $geoNear sorts by distance, so we keep the closest ones :
+            pipeline.push(doc! {"$limit": limit as i64});
+        }
+        let mut cursor = self
+            .available_users
+            .untyped()
+            .aggregate(pipeline, None)
+            .await?;
         let mut res: Vec<user::LocalizedUser> = Vec::new();
         while let Some(doc) = cursor.next().await {
             match doc {
                 Ok(document) => {
-                    let user: user::LocalizedUser = bson::from_document(document)?;
-                    res.push(user);
+                    let contact: StoredContact = documents::from_document(document)?;
+                    res.push(contact.into_localized(my_latitude, my_longitude));
                 }
                 Err(e) => return Err(e.into()),
             }
@@ -100,28 +231,59 @@ impl DataBaseInterface {
         return Ok(res);
     }
 
+    async fn remove_user(&self, phone_hash: &str) -> Result<bool, NearbyError> {
+        let delete_res = self
+            .available_users
+            .untyped()
+            .delete_one(doc! {"phone_number_hash": phone_hash}, None)
+            .await?;
+        return Ok(delete_res.deleted_count > 0);
+    }
+
     /**
      * Remove all user in database that are no longuer available.
      * Return the number of user deleted from the base.
      */
-    pub async fn remove_available_until(
-        self: &DataBaseInterface,
+    async fn remove_available_until(
+        &self,
         date_time: DateTime<FixedOffset>,
-    ) -> Result<i64, DatabaseError> {
+    ) -> Result<Vec<String>, NearbyError> {
         let date_time_utc: DateTime<Utc> = DateTime::from(date_time);
         let query = doc! {"available_until": doc! {"$lt": date_time_utc}};
-        let delete_res = self.available_collection.delete_many(query, None).await?;
+        // We need the removed users, to tell their contacts :
+        let options = FindOptions::builder()
+            .projection(doc! {"phone_number_hash": 1})
+            .build();
+        let mut cursor = self
+            .available_users
+            .untyped()
+            .find(query.clone(), options)
+            .await?;
+        let mut removed: Vec<String> = Vec::new();
+        while let Some(document) = cursor.next().await {
+            if let Ok(phone_hash) = document?.get_str("phone_number_hash") {
+                removed.push(String::from(phone_hash));
+            }
+        }
+        // Users that posted again in between are not removed :
+        let mut filter = query;
+        filter.insert("phone_number_hash", doc! {"$in": removed.clone()});
+        self.available_users
+            .untyped()
+            .delete_many(filter, None)
+            .await?;
 
-        return Ok(delete_res.deleted_count);
+        return Ok(removed);
     }
 
     /**
      * Usefull for testing, will return the number of deleted items.
      */
     #[cfg(test)]
-    pub async fn clear_database(self: &DataBaseInterface) -> Result<i64, DatabaseError> {
+    async fn clear_database(&self) -> Result<i64, NearbyError> {
         let res = self
-            .available_collection
+            .available_users
+            .untyped()
             .delete_many(doc! {}, None)
             .await
             .map(|res| res.deleted_count)?;
@@ -129,12 +291,25 @@ impl DataBaseInterface {
     }
 }
 
+/**
+ * When `my_contacts` is given, only users that are in it are kept : they must
+ * have picked us, and we must have picked them.
+ */
 fn create_nearby_stage(
-    phone_hash: &String,
+    now: DateTime<Utc>,
+    phone_hash: &str,
     latitude: f64,
     longitude: f64,
     max_distance_m: f32,
+    my_contacts: Option<&[String]>,
 ) -> bson::Document {
+    let mut query = doc! {
+        "contacts_phone_number_hash": phone_hash,
+        "available_until": not_expired(now),
+    };
+    if let Some(my_contacts) = my_contacts {
+        query.insert("phone_number_hash", doc! {"$in": my_contacts});
+    }
     return doc! {
         "$geoNear": doc! {
             "near": doc! {
@@ -143,28 +318,72 @@ fn create_nearby_stage(
             },
             "distanceField": "distance",
             "maxDistance": max_distance_m,
-            "query": doc! {"contacts_phone_number_hash": phone_hash.clone()},
+            "query": query,
             "spherical": true
         }
     };
 }
 
+/**
+ * The TTL index removes expired users about once a minute, so queries also
+ * filter them.
+ */
+fn not_expired(now: DateTime<Utc>) -> bson::Document {
+    return doc! {"$gt": now};
+}
+
+/**
+ * What contacts get to know about each other, as read by `StoredContact`.
+ */
 fn create_projection_stage() -> bson::Document {
-    return doc! {"$project": doc! {"phone_number_hash": 1, "distance": 1}};
+    return doc! {"$project": doc! {
+        "phone_number_hash": 1,
+        "distance": 1,
+        "location": 1,
+        "available_until": 1,
+        "location_precision": 1,
+        "status_message": 1,
+        "updated_at": 1,
+    }};
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::DateTime;
+    use crate::clock::FixedClock;
+    use chrono::Duration;
     use tokio;
 
     async fn prepare_test() -> DataBaseInterface {
-        let database = DataBaseInterface::new().await.expect("Can't connect to DB");
+        let database = DataBaseInterface::new(&DatabaseConfiguration::default())
+            .await
+            .expect("Can't connect to DB");
         let deleted = database.clear_database().await.expect("Can't clean DB");
         println!("{} document deleted", deleted);
         return database;
     }
+
+    /**
+     * Tests run in parallel : those clearing the database while others insert
+     * users get a database of their own.
+     */
+    async fn prepare_test_in(database_name: &str) -> DataBaseInterface {
+        let configuration = DatabaseConfiguration {
+            database_name: String::from(database_name),
+            ..DatabaseConfiguration::default()
+        };
+        let database = DataBaseInterface::new(&configuration)
+            .await
+            .expect("Can't connect to DB");
+        database.clear_database().await.expect("Can't clean DB");
+        return database;
+    }
+
+    fn available_until() -> DateTime<FixedOffset> {
+        // Expired users are hidden :
+        DateTime::from(Utc::now() + Duration::hours(1))
+    }
+
     #[tokio::test]
     async fn test_we_can_insert_new_user() {
         let database = prepare_test().await;
@@ -173,8 +392,9 @@ mod tests {
             phone_number_hash: String::from("15645612"),
             latitude: 43.2255228,
             longitude: 6.3516515645,
-            available_until: DateTime::parse_from_rfc3339("2021-05-21T18:21:43+00:00")
-                .expect("Can't parse date"),
+            available_until: available_until(),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec![],
         };
         let res = database
@@ -189,7 +409,10 @@ mod tests {
             .await
             .expect("Can't add user");
 
-        assert!(std::matches!(res2, ReplacedOrInserted::Replaced));
+        assert!(std::matches!(
+            res2,
+            ReplacedOrInserted::Replaced(changes) if !changes.any()
+        ));
     }
 
     #[tokio::test]
@@ -199,8 +422,9 @@ mod tests {
             phone_number_hash: String::from("Sylverster Staline"),
             latitude: 43.00001,
             longitude: 6.00001,
-            available_until: DateTime::parse_from_rfc3339("2021-05-21T18:21:43+00:00")
-                .expect("Can't parse date"),
+            available_until: available_until(),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec![
                 "John Lenine".to_string(),
                 "Didier CrouteChef".to_string(),
@@ -215,8 +439,9 @@ mod tests {
             phone_number_hash: String::from("Didier CrouteChef"),
             latitude: 42.0000,
             longitude: 5.0000,
-            available_until: DateTime::parse_from_rfc3339("2021-05-21T18:21:43+00:00")
-                .expect("Can't parse date"),
+            available_until: available_until(),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec!["John Lenine".to_string()],
         };
         database
@@ -228,8 +453,9 @@ mod tests {
             phone_number_hash: String::from("Unknown Man"),
             latitude: 43.0000,
             longitude: 6.0000,
-            available_until: DateTime::parse_from_rfc3339("2021-05-21T18:21:43+00:00")
-                .expect("Can't parse date"),
+            available_until: available_until(),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec!["Unknwon Man's Friend".to_string()],
         };
         database
@@ -239,7 +465,14 @@ mod tests {
 
         let my_phone_hash = "John Lenine".to_string();
         let contact_availables = database
-            .get_contacts_available_nearby(&my_phone_hash, 43.000_f64, 6.000_f64, 1000_f32)
+            .get_contacts_available_nearby(
+                &my_phone_hash,
+                43.000_f64,
+                6.000_f64,
+                1000_f32,
+                None,
+                false,
+            )
             .await
             .expect("Can't get availables contacts");
 
@@ -250,13 +483,87 @@ mod tests {
         assert_eq!(contact_availables.len(), 1);
         assert_eq!(
             contact_availables
-                .get(0)
+                .first()
                 .expect("Not enough returned values")
                 .phone_number_hash,
             sylvester.phone_number_hash
         )
     }
 
+    #[tokio::test]
+    async fn test_mutual_mode_requires_both_contacts() {
+        let database = prepare_test_in("nearby_test_mutual").await;
+        let available_until = available_until();
+        for (phone_number_hash, contacts) in [
+            ("John Lenine", vec!["Sylverster Staline"]),
+            ("Sylverster Staline", vec!["John Lenine"]),
+            ("Didier CrouteChef", vec!["John Lenine"]),
+        ] {
+            let user = user::User {
+                phone_number_hash: String::from(phone_number_hash),
+                latitude: 43.0,
+                longitude: 6.0,
+                available_until,
+                location_precision: user::LocationPrecision::Exact,
+                status_message: None,
+                contacts_phone_number_hash: contacts.iter().map(|c| c.to_string()).collect(),
+            };
+            database
+                .set_user_available(&user)
+                .await
+                .expect("Can't add user");
+        }
+
+        // Didier picked John, but John didn't pick Didier :
+        let contact_availables = database
+            .get_contacts_available_nearby("John Lenine", 43.0, 6.0, 1000_f32, None, true)
+            .await
+            .expect("Can't get availables contacts");
+        assert_eq!(contact_availables.len(), 1);
+        assert_eq!(
+            contact_availables
+                .first()
+                .expect("Not enough returned values")
+                .phone_number_hash,
+            "Sylverster Staline"
+        );
+
+        let contact_availables = database
+            .get_contacts_available_nearby("John Lenine", 43.0, 6.0, 1000_f32, None, false)
+            .await
+            .expect("Can't get availables contacts");
+        assert_eq!(contact_availables.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_we_can_remove_a_user() {
+        let database = prepare_test_in("nearby_test_remove").await;
+        let user = user::User {
+            phone_number_hash: String::from("15645612"),
+            latitude: 43.2255228,
+            longitude: 6.3516515645,
+            available_until: available_until(),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![],
+        };
+        database
+            .set_user_available(&user)
+            .await
+            .expect("Can't add user");
+
+        let removed = database
+            .remove_user(&user.phone_number_hash)
+            .await
+            .expect("Can't remove user");
+        assert!(removed);
+        let removed = database
+            .remove_user(&user.phone_number_hash)
+            .await
+            .expect("Can't remove user");
+        assert!(!removed);
+    }
+
     #[tokio::test]
     async fn test_we_remove_user_no_longuer_available() {
         let database = prepare_test().await;
@@ -265,8 +572,9 @@ mod tests {
             phone_number_hash: String::from("Available"),
             latitude: 43.2255228,
             longitude: 6.3516515645,
-            available_until: DateTime::parse_from_rfc3339("2021-05-21T18:21:00+00:00")
-                .expect("Can't parse date"),
+            available_until: DateTime::from(Utc::now() + Duration::hours(2)),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec![],
         };
 
@@ -274,8 +582,9 @@ mod tests {
             phone_number_hash: String::from("Not Available"),
             latitude: 43.2255228,
             longitude: 6.3516515645,
-            available_until: DateTime::parse_from_rfc3339("2021-05-21T18:20:00+00:00")
-                .expect("Can't parse date"),
+            available_until: DateTime::from(Utc::now() + Duration::hours(1)),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec![],
         };
 
@@ -289,17 +598,17 @@ mod tests {
             .await
             .expect("Can't add user");
 
-        let now =
-            DateTime::parse_from_rfc3339("2021-05-21T18:20:30+00:00").expect("Can't parse date !");
+        // Not in the past, or the TTL index could remove it before us :
+        let later = DateTime::from(Utc::now() + Duration::minutes(90));
 
-        let count = database
-            .remove_available_until(now)
+        let removed = database
+            .remove_available_until(later)
             .await
             .expect("Can't remove users");
-        assert_eq!(count, 1);
+        assert_eq!(removed.len(), 1);
 
         let mut cursor = database
-            .available_collection
+            .available_collection()
             .find(doc! {}, None)
             .await
             .expect("Can't get users");
@@ -314,8 +623,48 @@ mod tests {
         }
         assert_eq!(availables_users_hash.len(), 1);
         assert_eq!(
-            *availables_users_hash.get(0).expect("Incorrect length"),
+            *availables_users_hash.first().expect("Incorrect length"),
             available.phone_number_hash
         );
     }
+
+    #[tokio::test]
+    async fn test_expired_users_are_hidden() {
+        // Real dates, or the TTL index could remove users during the test :
+        let clock = FixedClock::at(&Utc::now().to_rfc3339());
+        let database = prepare_test_in("nearby_test_expired")
+            .await
+            .with_clock(clock.clone());
+        let user = user::User {
+            phone_number_hash: String::from("Soon Expired"),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: available_until(),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![String::from("Me")],
+        };
+        database
+            .set_user_available(&user)
+            .await
+            .expect("Can't add user");
+        let contact_availables = database
+            .get_contacts_available_nearby("Me", 43.0, 6.0, 1000_f32, None, false)
+            .await
+            .expect("Can't get availables contacts");
+        assert_eq!(contact_availables.len(), 1);
+
+        // Still stored, but not visible :
+        clock.advance(Duration::hours(2));
+        assert!(database
+            .get_user("Soon Expired")
+            .await
+            .expect("Can't get user")
+            .is_none());
+        let contact_availables = database
+            .get_contacts_available_nearby("Me", 43.0, 6.0, 1000_f32, None, false)
+            .await
+            .expect("Can't get availables contacts");
+        assert!(contact_availables.is_empty());
+    }
 }
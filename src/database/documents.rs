@@ -0,0 +1,308 @@
+use crate::database::in_memory_store::bearing_deg;
+use crate::error::NearbyError;
+use crate::models::user::{self, LocationPrecision};
+use chrono::{DateTime, Utc};
+use mongodb::{
+    bson::{self, serde_helpers::chrono_datetime_as_bson_datetime, Document},
+    options::{FindOneAndReplaceOptions, FindOneOptions},
+    Collection,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::marker::PhantomData;
+
+// How documents are stored, independently of the API types : both can evolve
+// without breaking the other.
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum GeoJsonType {
+    Point,
+}
+
+/**
+ * A GeoJSON point, as needed by the `2dsphere` index.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GeoPoint {
+    #[serde(rename = "type")]
+    pub kind: GeoJsonType,
+    /// Longitude first !
+    pub coordinates: [f64; 2],
+}
+
+impl GeoPoint {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        GeoPoint {
+            kind: GeoJsonType::Point,
+            coordinates: [longitude, latitude],
+        }
+    }
+
+    pub fn latitude(&self) -> f64 {
+        return self.coordinates[1];
+    }
+
+    pub fn longitude(&self) -> f64 {
+        return self.coordinates[0];
+    }
+}
+
+/**
+ * An available user, in the available collection.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StoredUser {
+    pub phone_number_hash: String,
+    pub location: GeoPoint,
+    /// A BSON date, so the TTL index can remove the user.
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub available_until: DateTime<Utc>,
+    pub contacts_phone_number_hash: Vec<String>,
+    /// Users stored before it existed are read as approximate.
+    #[serde(default)]
+    pub location_precision: LocationPrecision,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_message: Option<String>,
+    /// Set by the store at each post, missing for users stored before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<bson::DateTime>,
+}
+
+/**
+ * A user found by the nearby search, with the distance computed by `$geoNear`.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StoredContact {
+    pub phone_number_hash: String,
+    pub distance: f64,
+    pub location: GeoPoint,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub available_until: DateTime<Utc>,
+    #[serde(default)]
+    pub location_precision: LocationPrecision,
+    #[serde(default)]
+    pub status_message: Option<String>,
+    #[serde(default)]
+    pub updated_at: Option<bson::DateTime>,
+}
+
+impl From<&user::User> for StoredUser {
+    fn from(user: &user::User) -> Self {
+        StoredUser {
+            phone_number_hash: user.phone_number_hash.clone(),
+            location: GeoPoint::new(user.latitude, user.longitude),
+            available_until: DateTime::from(user.available_until),
+            contacts_phone_number_hash: user.contacts_phone_number_hash.clone(),
+            location_precision: user.location_precision,
+            status_message: user.status_message.clone(),
+            updated_at: None,
+        }
+    }
+}
+
+impl From<StoredUser> for user::User {
+    fn from(stored: StoredUser) -> Self {
+        user::User {
+            phone_number_hash: stored.phone_number_hash,
+            latitude: stored.location.latitude(),
+            longitude: stored.location.longitude(),
+            available_until: DateTime::from(stored.available_until),
+            contacts_phone_number_hash: stored.contacts_phone_number_hash,
+            location_precision: stored.location_precision,
+            status_message: stored.status_message,
+        }
+    }
+}
+
+impl StoredContact {
+    /**
+     * The contact as seen by the user searching from this location.
+     */
+    pub fn into_localized(self, latitude: f64, longitude: f64) -> user::LocalizedUser {
+        user::LocalizedUser {
+            phone_number_hash: self.phone_number_hash,
+            distance: Some(self.distance as f32),
+            distance_bucket: None,
+            location_precision: self.location_precision,
+            available_until: Some(DateTime::from(self.available_until)),
+            status_message: self.status_message,
+            bearing: bearing_deg(
+                latitude,
+                longitude,
+                self.location.latitude(),
+                self.location.longitude(),
+            ),
+            direction: None,
+            updated_at: self
+                .updated_at
+                .map(|updated_at| DateTime::from(updated_at.0)),
+        }
+    }
+}
+
+pub fn to_document<T: Serialize>(value: &T) -> Result<Document, NearbyError> {
+    return Ok(bson::to_document(value)?);
+}
+
+pub fn from_document<T: DeserializeOwned>(document: Document) -> Result<T, NearbyError> {
+    return Ok(bson::from_document(document)?);
+}
+
+/**
+ * Our driver only has untyped collections : this one reads and writes `T`.
+ * Use `untyped` for projections and the other queries.
+ */
+#[derive(Clone)]
+pub struct TypedCollection<T> {
+    collection: Collection,
+    documents: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> TypedCollection<T> {
+    pub fn new(collection: Collection) -> Self {
+        TypedCollection {
+            collection,
+            documents: PhantomData,
+        }
+    }
+
+    pub fn untyped(&self) -> &Collection {
+        return &self.collection;
+    }
+
+    pub async fn find_one(
+        &self,
+        filter: Document,
+        options: impl Into<Option<FindOneOptions>>,
+    ) -> Result<Option<T>, NearbyError> {
+        return match self.collection.find_one(filter, options).await? {
+            Some(document) => Ok(Some(from_document(document)?)),
+            None => Ok(None),
+        };
+    }
+
+    /**
+     * Return the replaced document, or the new one, depending on the options.
+     */
+    pub async fn find_one_and_replace(
+        &self,
+        filter: Document,
+        replacement: &T,
+        options: impl Into<Option<FindOneAndReplaceOptions>>,
+    ) -> Result<Option<T>, NearbyError> {
+        let replaced = self
+            .collection
+            .find_one_and_replace(filter, to_document(replacement)?, options)
+            .await?;
+        return match replaced {
+            Some(document) => Ok(Some(from_document(document)?)),
+            None => Ok(None),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::{doc, Bson};
+
+    fn peppa() -> user::User {
+        user::User {
+            phone_number_hash: String::from("01234"),
+            latitude: 43.5,
+            longitude: 5.8952895,
+            available_until: DateTime::parse_from_rfc3339("2021-01-01T12:21:33+01:00")
+                .expect("Can't parse date"),
+            location_precision: LocationPrecision::Exact,
+            status_message: Some(String::from("At the beach")),
+            contacts_phone_number_hash: vec![String::from("56789"), String::from("0000000")],
+        }
+    }
+
+    #[test]
+    fn test_users_are_stored_as_geojson() {
+        let user = peppa();
+        let document = to_document(&StoredUser::from(&user)).expect("Can't serialize user");
+        assert_eq!(
+            document
+                .get_str("phone_number_hash")
+                .expect("Can't find phone number hash"),
+            user.phone_number_hash
+        );
+        let location = document
+            .get_document("location")
+            .expect("Can't find location");
+        assert_eq!(location.get_str("type").expect("Can't find type"), "Point");
+        let coordinates = location
+            .get_array("coordinates")
+            .expect("Can't find coordinates");
+        assert_eq!(coordinates[0].as_f64(), Some(user.longitude));
+        assert_eq!(coordinates[1].as_f64(), Some(user.latitude));
+        // A date, not a string, or the TTL index ignores it :
+        assert_eq!(
+            document.get("available_until"),
+            Some(&Bson::DateTime(DateTime::from(user.available_until)))
+        );
+        assert_eq!(
+            document
+                .get_array("contacts_phone_number_hash")
+                .expect("Can't find contacts")
+                .len(),
+            2
+        );
+        assert_eq!(
+            document
+                .get_str("location_precision")
+                .expect("Can't find precision"),
+            "exact"
+        );
+    }
+
+    #[test]
+    fn test_users_can_be_read_back() {
+        let user = peppa();
+        let document = to_document(&StoredUser::from(&user)).expect("Can't serialize user");
+        let read_back: user::User = from_document::<StoredUser>(document)
+            .expect("Can't read user back")
+            .into();
+        assert_eq!(read_back.phone_number_hash, user.phone_number_hash);
+        assert!((read_back.latitude - user.latitude).abs() < 0.000_001);
+        assert!((read_back.longitude - user.longitude).abs() < 0.000_001);
+        assert_eq!(read_back.available_until, user.available_until);
+        assert_eq!(
+            read_back.contacts_phone_number_hash,
+            user.contacts_phone_number_hash
+        );
+        assert_eq!(read_back.location_precision, user.location_precision);
+        assert_eq!(read_back.status_message, user.status_message);
+    }
+
+    #[test]
+    fn test_old_documents_can_be_read() {
+        // Stored before location_precision, with an _id like all documents :
+        let document = doc! {
+            "_id": bson::oid::ObjectId::new(),
+            "phone_number_hash": "v1:peppa",
+            "location": {"type": "Point", "coordinates": [6.0, 43.0]},
+            "available_until": Utc::now(),
+            "contacts_phone_number_hash": ["v1:rebecca"],
+        };
+        let stored: StoredUser = from_document(document).expect("Can't read old user");
+        assert_eq!(stored.location.latitude(), 43.0);
+        assert_eq!(stored.location_precision, LocationPrecision::Approximate);
+
+        let contact: StoredContact = from_document(doc! {
+            "_id": bson::oid::ObjectId::new(),
+            "phone_number_hash": "v1:rebecca",
+            "distance": 412.5,
+            "location": {"type": "Point", "coordinates": [6.0, 43.01]},
+            "available_until": Utc::now(),
+            "location_precision": "exact",
+        })
+        .expect("Can't read contact");
+        let contact = contact.into_localized(43.0, 6.0);
+        assert_eq!(contact.distance, Some(412.5));
+        assert_eq!(contact.status_message, None);
+        assert_eq!(contact.updated_at, None);
+        assert!(contact.bearing.expect("Contact is north") < 1.0);
+    }
+}
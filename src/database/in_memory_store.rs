@@ -0,0 +1,524 @@
+use crate::clock::{SharedClock, SystemClock};
+use crate::database::availability_store::AvailabilityStore;
+use crate::database::database_interface::{ReplacedOrInserted, UserChanges};
+use crate::error::NearbyError;
+use crate::models::user;
+use chrono::{DateTime, FixedOffset, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/**
+ * Radius used by MongoDB for spherical distances, we use the same one so both
+ * backends return the same distances.
+ */
+const EARTH_RADIUS_M: f64 = 6_378_100.0;
+
+/**
+ * A user as kept by the store.
+ */
+struct Entry {
+    user: user::User,
+    /// When the user last posted.
+    updated_at: DateTime<Utc>,
+}
+
+/**
+ * An availability store that keeps everything in process memory.
+ * Usefull for tests, and for small instances that don't need a MongoDB.
+ * Nothing is persisted : all users are lost when the server stops.
+ */
+#[derive(Clone)]
+pub struct InMemoryStore {
+    users: Arc<RwLock<HashMap<String, Entry>>>,
+    clock: SharedClock,
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        InMemoryStore {
+            users: Arc::new(RwLock::new(HashMap::new())),
+            clock: SystemClock::shared(),
+        }
+    }
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        InMemoryStore::default()
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        return self;
+    }
+
+    /**
+     * Like the query filter of MongoDB : expired users are hidden even before
+     * the cleaner removes them.
+     */
+    fn is_available(&self, user: &user::User) -> bool {
+        return user.available_until > self.clock.now();
+    }
+
+    fn lock_error() -> NearbyError {
+        NearbyError::Internal(String::from("In memory store is poisoned"))
+    }
+}
+
+impl AvailabilityStore for InMemoryStore {
+    async fn set_user_available(
+        &self,
+        user: &user::User,
+    ) -> Result<ReplacedOrInserted, NearbyError> {
+        let mut users = self.users.write().map_err(|_| Self::lock_error())?;
+        let entry = Entry {
+            user: user.clone(),
+            updated_at: self.clock.now(),
+        };
+        return Ok(match users.insert(user.phone_number_hash.clone(), entry) {
+            Some(previous) => {
+                ReplacedOrInserted::Replaced(UserChanges::between(&previous.user, user))
+            }
+            None => ReplacedOrInserted::Inserted,
+        });
+    }
+
+    async fn get_user(&self, phone_hash: &str) -> Result<Option<user::User>, NearbyError> {
+        let users = self.users.read().map_err(|_| Self::lock_error())?;
+        return Ok(users
+            .get(phone_hash)
+            .map(|entry| &entry.user)
+            .filter(|user| self.is_available(user))
+            .cloned());
+    }
+
+    async fn get_contacts_available_nearby(
+        &self,
+        my_phone_hash: &str,
+        my_latitude: f64,
+        my_longitude: f64,
+        max_distance_m: f32,
+        limit: Option<u32>,
+        mutual: bool,
+    ) -> Result<Vec<user::LocalizedUser>, NearbyError> {
+        let users = self.users.read().map_err(|_| Self::lock_error())?;
+        let my_contacts = if mutual {
+            match users
+                .get(my_phone_hash)
+                .filter(|me| self.is_available(&me.user))
+            {
+                Some(me) => Some(&me.user.contacts_phone_number_hash),
+                None => return Ok(Vec::new()),
+            }
+        } else {
+            None
+        };
+        let mut res: Vec<user::LocalizedUser> = users
+            .values()
+            .filter(|entry| self.is_available(&entry.user))
+            .filter(|Entry { user, .. }| {
+                user.contacts_phone_number_hash
+                    .iter()
+                    .any(|contact| contact == my_phone_hash)
+            })
+            .filter(|Entry { user, .. }| match my_contacts {
+                Some(my_contacts) => my_contacts.contains(&user.phone_number_hash),
+                None => true,
+            })
+            .map(|entry| {
+                let user = &entry.user;
+                let distance =
+                    haversine_distance_m(my_latitude, my_longitude, user.latitude, user.longitude)
+                        as f32;
+                (entry, distance)
+            })
+            .filter(|(_, distance)| *distance <= max_distance_m)
+            .map(
+                |(Entry { user, updated_at }, distance)| user::LocalizedUser {
+                    phone_number_hash: user.phone_number_hash.clone(),
+                    distance: Some(distance),
+                    distance_bucket: None,
+                    location_precision: user.location_precision,
+                    available_until: Some(user.available_until),
+                    status_message: user.status_message.clone(),
+                    bearing: bearing_deg(my_latitude, my_longitude, user.latitude, user.longitude),
+                    direction: None,
+                    updated_at: Some(DateTime::from(*updated_at)),
+                },
+            )
+            .collect();
+        // Like $geoNear, the closest users come first :
+        res.sort_by(|a, b| {
+            a.distance
+                .unwrap_or_default()
+                .total_cmp(&b.distance.unwrap_or_default())
+        });
+        if let Some(limit) = limit {
+            res.truncate(limit as usize);
+        }
+        return Ok(res);
+    }
+
+    async fn remove_user(&self, phone_hash: &str) -> Result<bool, NearbyError> {
+        let mut users = self.users.write().map_err(|_| Self::lock_error())?;
+        return Ok(users.remove(phone_hash).is_some());
+    }
+
+    async fn remove_available_until(
+        &self,
+        date_time: DateTime<FixedOffset>,
+    ) -> Result<Vec<String>, NearbyError> {
+        let mut users = self.users.write().map_err(|_| Self::lock_error())?;
+        let removed: Vec<String> = users
+            .values()
+            .filter(|entry| entry.user.available_until < date_time)
+            .map(|entry| entry.user.phone_number_hash.clone())
+            .collect();
+        for phone_hash in removed.iter() {
+            users.remove(phone_hash);
+        }
+        return Ok(removed);
+    }
+
+    #[cfg(test)]
+    async fn clear_database(&self) -> Result<i64, NearbyError> {
+        let mut users = self.users.write().map_err(|_| Self::lock_error())?;
+        let count = users.len();
+        users.clear();
+        return Ok(count as i64);
+    }
+}
+
+/**
+ * Great circle distance between two points given in decimal degrees.
+ */
+pub fn haversine_distance_m(
+    latitude_1: f64,
+    longitude_1: f64,
+    latitude_2: f64,
+    longitude_2: f64,
+) -> f64 {
+    let delta_latitude = (latitude_2 - latitude_1).to_radians();
+    let delta_longitude = (longitude_2 - longitude_1).to_radians();
+    let a = (delta_latitude / 2.0).sin().powi(2)
+        + latitude_1.to_radians().cos()
+            * latitude_2.to_radians().cos()
+            * (delta_longitude / 2.0).sin().powi(2);
+    return 2.0 * EARTH_RADIUS_M * a.sqrt().asin();
+}
+
+/**
+ * Initial bearing from the first point to the second one, in degrees clockwise
+ * from north. None when both points are less than a meter apart.
+ */
+pub fn bearing_deg(
+    latitude_1: f64,
+    longitude_1: f64,
+    latitude_2: f64,
+    longitude_2: f64,
+) -> Option<f32> {
+    if haversine_distance_m(latitude_1, longitude_1, latitude_2, longitude_2) < 1.0 {
+        return None;
+    }
+    let delta_longitude = (longitude_2 - longitude_1).to_radians();
+    let (latitude_1, latitude_2) = (latitude_1.to_radians(), latitude_2.to_radians());
+    let y = delta_longitude.sin() * latitude_2.cos();
+    let x = latitude_1.cos() * latitude_2.sin()
+        - latitude_1.sin() * latitude_2.cos() * delta_longitude.cos();
+    return Some(y.atan2(x).to_degrees().rem_euclid(360.0) as f32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use std::sync::Arc;
+
+    const NOW: &str = "2021-05-21T18:00:00+00:00";
+
+    fn available_until() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2021-05-21T18:21:43+00:00").expect("Can't parse date")
+    }
+
+    /**
+     * Users are available until `available_until()`, as seen by this store.
+     */
+    fn test_store(clock: Arc<FixedClock>) -> InMemoryStore {
+        return InMemoryStore::new().with_clock(clock);
+    }
+
+    #[test]
+    fn test_haversine_distance() {
+        // One degree of latitude is about 111 km :
+        let distance = haversine_distance_m(43.0, 6.0, 44.0, 6.0);
+        assert!((distance - 111_319.0).abs() < 100.0);
+        assert!(haversine_distance_m(43.0, 6.0, 43.0, 6.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_bearing() {
+        let north = bearing_deg(43.0, 6.0, 44.0, 6.0).expect("Points are apart");
+        assert!(north.abs() < 0.001);
+        let west = bearing_deg(43.0, 6.0, 43.0, 5.0).expect("Points are apart");
+        assert!((west - 270.0).abs() < 1.0);
+        assert_eq!(bearing_deg(43.0, 6.0, 43.0, 6.0), None);
+    }
+
+    #[tokio::test]
+    async fn test_we_can_insert_new_user() {
+        let database = test_store(FixedClock::at(NOW));
+        let user = user::User {
+            phone_number_hash: String::from("15645612"),
+            latitude: 43.2255228,
+            longitude: 6.3516515645,
+            available_until: available_until(),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![],
+        };
+        let res = database
+            .set_user_available(&user)
+            .await
+            .expect("Can't add user");
+        assert!(std::matches!(res, ReplacedOrInserted::Inserted));
+
+        let res2 = database
+            .set_user_available(&user)
+            .await
+            .expect("Can't add user");
+        assert!(std::matches!(
+            res2,
+            ReplacedOrInserted::Replaced(changes) if !changes.any()
+        ));
+
+        let moved = user::User {
+            latitude: 43.3,
+            contacts_phone_number_hash: vec![String::from("Rebecca")],
+            ..user
+        };
+        let res3 = database
+            .set_user_available(&moved)
+            .await
+            .expect("Can't add user");
+        assert!(std::matches!(
+            res3,
+            ReplacedOrInserted::Replaced(UserChanges {
+                location: true,
+                window: false,
+                contacts: true,
+                status: false,
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_we_can_get_available_contacts_nearby() {
+        let database = test_store(FixedClock::at(NOW));
+        let users = [
+            user::User {
+                phone_number_hash: String::from("Sylverster Staline"),
+                latitude: 43.00001,
+                longitude: 6.00001,
+                available_until: available_until(),
+                location_precision: user::LocationPrecision::Exact,
+                status_message: None,
+                contacts_phone_number_hash: vec!["John Lenine".to_string()],
+            },
+            user::User {
+                phone_number_hash: String::from("Didier CrouteChef"),
+                latitude: 42.0000,
+                longitude: 5.0000,
+                available_until: available_until(),
+                location_precision: user::LocationPrecision::Exact,
+                status_message: None,
+                contacts_phone_number_hash: vec!["John Lenine".to_string()],
+            },
+            user::User {
+                phone_number_hash: String::from("Unknown Man"),
+                latitude: 43.0000,
+                longitude: 6.0000,
+                available_until: available_until(),
+                location_precision: user::LocationPrecision::Exact,
+                status_message: None,
+                contacts_phone_number_hash: vec!["Unknwon Man's Friend".to_string()],
+            },
+        ];
+        for user in users.iter() {
+            database
+                .set_user_available(user)
+                .await
+                .expect("Can't add user");
+        }
+
+        let contact_availables = database
+            .get_contacts_available_nearby(
+                "John Lenine",
+                43.000_f64,
+                6.000_f64,
+                1000_f32,
+                None,
+                false,
+            )
+            .await
+            .expect("Can't get availables contacts");
+
+        assert_eq!(contact_availables.len(), 1);
+        let sylvester = contact_availables
+            .first()
+            .expect("Not enough returned values");
+        assert_eq!(sylvester.phone_number_hash, "Sylverster Staline");
+        let distance = sylvester.distance.expect("Store must give distances");
+        assert!(distance > 0.0 && distance < 2.0);
+        assert_eq!(sylvester.available_until, Some(available_until()));
+        assert!(sylvester.bearing.is_some());
+        assert_eq!(
+            sylvester.updated_at,
+            Some(DateTime::parse_from_rfc3339(NOW).expect("Can't parse date"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nearby_contacts_are_limited() {
+        let database = test_store(FixedClock::at(NOW));
+        for (index, phone_number_hash) in ["Close", "Closer", "Far"].iter().enumerate() {
+            let user = user::User {
+                phone_number_hash: phone_number_hash.to_string(),
+                latitude: 43.0 + [0.002, 0.001, 0.003][index],
+                longitude: 6.0,
+                available_until: available_until(),
+                location_precision: user::LocationPrecision::Exact,
+                status_message: None,
+                contacts_phone_number_hash: vec!["John Lenine".to_string()],
+            };
+            database
+                .set_user_available(&user)
+                .await
+                .expect("Can't add user");
+        }
+
+        let contact_availables = database
+            .get_contacts_available_nearby("John Lenine", 43.0, 6.0, 1000_f32, Some(2), false)
+            .await
+            .expect("Can't get availables contacts");
+        let hashes: Vec<&str> = contact_availables
+            .iter()
+            .map(|contact| contact.phone_number_hash.as_str())
+            .collect();
+        assert_eq!(hashes, vec!["Closer", "Close"]);
+    }
+
+    #[tokio::test]
+    async fn test_mutual_mode_requires_both_contacts() {
+        let database = test_store(FixedClock::at(NOW));
+        for (phone_number_hash, contacts) in [
+            ("John Lenine", vec!["Sylverster Staline"]),
+            ("Sylverster Staline", vec!["John Lenine"]),
+            ("Didier CrouteChef", vec!["John Lenine"]),
+        ] {
+            let user = user::User {
+                phone_number_hash: String::from(phone_number_hash),
+                latitude: 43.0,
+                longitude: 6.0,
+                available_until: available_until(),
+                location_precision: user::LocationPrecision::Exact,
+                status_message: None,
+                contacts_phone_number_hash: contacts.iter().map(|c| c.to_string()).collect(),
+            };
+            database
+                .set_user_available(&user)
+                .await
+                .expect("Can't add user");
+        }
+
+        let contact_availables = database
+            .get_contacts_available_nearby("John Lenine", 43.0, 6.0, 1000_f32, None, true)
+            .await
+            .expect("Can't get availables contacts");
+        assert_eq!(contact_availables.len(), 1);
+        assert_eq!(
+            contact_availables[0].phone_number_hash,
+            "Sylverster Staline"
+        );
+
+        // Somebody who is not available has no mutual contacts :
+        let contact_availables = database
+            .get_contacts_available_nearby("Hugo Chat Vez", 43.0, 6.0, 1000_f32, None, true)
+            .await
+            .expect("Can't get availables contacts");
+        assert!(contact_availables.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_we_remove_user_no_longuer_available() {
+        let database = test_store(FixedClock::at(NOW));
+        for (phone_number_hash, available_until) in [
+            ("Available", "2021-05-21T18:21:00+00:00"),
+            ("Not Available", "2021-05-21T18:20:00+00:00"),
+        ] {
+            let user = user::User {
+                phone_number_hash: String::from(phone_number_hash),
+                latitude: 43.2255228,
+                longitude: 6.3516515645,
+                available_until: DateTime::parse_from_rfc3339(available_until)
+                    .expect("Can't parse date"),
+                location_precision: user::LocationPrecision::Exact,
+                status_message: None,
+                contacts_phone_number_hash: vec![],
+            };
+            database
+                .set_user_available(&user)
+                .await
+                .expect("Can't add user");
+        }
+
+        let now =
+            DateTime::parse_from_rfc3339("2021-05-21T18:20:30+00:00").expect("Can't parse date !");
+        let removed = database
+            .remove_available_until(now)
+            .await
+            .expect("Can't remove users");
+        assert_eq!(removed, vec!["Not Available"]);
+
+        let users = database.users.read().expect("Poisoned store");
+        assert_eq!(users.len(), 1);
+        assert!(users.contains_key("Available"));
+    }
+
+    #[tokio::test]
+    async fn test_expired_users_are_hidden() {
+        let clock = FixedClock::at(NOW);
+        let database = test_store(clock.clone());
+        let user = user::User {
+            phone_number_hash: String::from("Soon Expired"),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: available_until(),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![String::from("Me")],
+        };
+        database
+            .set_user_available(&user)
+            .await
+            .expect("Can't add user");
+        let contact_availables = database
+            .get_contacts_available_nearby("Me", 43.0, 6.0, 1000_f32, None, false)
+            .await
+            .expect("Can't get availables contacts");
+        assert_eq!(contact_availables.len(), 1);
+
+        // Not removed yet, but not visible :
+        clock.advance(chrono::Duration::minutes(30));
+        assert!(database
+            .get_user("Soon Expired")
+            .await
+            .expect("Can't get user")
+            .is_none());
+        let contact_availables = database
+            .get_contacts_available_nearby("Me", 43.0, 6.0, 1000_f32, None, false)
+            .await
+            .expect("Can't get availables contacts");
+        assert!(contact_availables.is_empty());
+    }
+}
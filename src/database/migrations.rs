@@ -0,0 +1,280 @@
+use crate::configuration::DatabaseConfiguration;
+use crate::error::NearbyError;
+use chrono::Utc;
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document, Regex},
+    Client, Collection, Database,
+};
+use std::collections::HashSet;
+
+/// Versions already applied, with when.
+const MIGRATIONS_COLLECTION: &str = "schema_migrations";
+
+/**
+ * What a migration does to the documents matching its filter.
+ */
+pub enum Change {
+    Update(Document),
+    Delete,
+    /// Keep only the document with the latest `available_until` for each value
+    /// of this field.
+    RemoveDuplicates(&'static str),
+}
+
+/**
+ * One step over the available users collection. Steps are applied in order of
+ * `version`, each one only once : never change a step that was released, add a
+ * new one instead.
+ */
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    filter: Document,
+    change: Change,
+}
+
+/**
+ * What was done, or what would be done with a dry run.
+ */
+pub struct MigrationReport {
+    pub version: u32,
+    pub description: &'static str,
+    /// Documents updated or deleted.
+    pub documents: i64,
+}
+
+/**
+ * All the migrations, by version.
+ */
+pub fn migrations() -> Vec<Migration> {
+    return vec![
+        Migration {
+            version: 1,
+            description: "users stored before location_precision are located approximately",
+            filter: doc! {"location_precision": {"$exists": false}},
+            change: Change::Update(doc! {"$set": {"location_precision": "approximate"}}),
+        },
+        Migration {
+            version: 2,
+            description: "remove users stored with plain hashes, before keyed hashes",
+            filter: doc! {"phone_number_hash": {"$not": Regex {
+                pattern: String::from("^v[0-9]+:"),
+                options: String::new(),
+            }}},
+            change: Change::Delete,
+        },
+        Migration {
+            version: 3,
+            description: "keep only the latest availability of each user, before the unique index",
+            filter: doc! {},
+            change: Change::RemoveDuplicates("phone_number_hash"),
+        },
+    ];
+}
+
+/**
+ * Open the database without `schema::ensure_schema` : the unique index can't be
+ * created before the migrations remove duplicated users.
+ */
+pub async fn connect(configuration: &DatabaseConfiguration) -> Result<Database, NearbyError> {
+    let client = Client::with_uri_str(&configuration.mongo_uri).await?;
+    return Ok(client.database(&configuration.database_name));
+}
+
+/**
+ * Return the migrations that were not applied yet.
+ */
+pub async fn pending(database: &Database) -> Result<Vec<Migration>, NearbyError> {
+    let mut cursor = database
+        .collection(MIGRATIONS_COLLECTION)
+        .find(doc! {}, None)
+        .await?;
+    let mut applied: HashSet<i64> = HashSet::new();
+    while let Some(document) = cursor.next().await {
+        if let Ok(version) = document?.get_i64("_id") {
+            applied.insert(version);
+        }
+    }
+    return Ok(migrations()
+        .into_iter()
+        .filter(|migration| !applied.contains(&(migration.version as i64)))
+        .collect());
+}
+
+/**
+ * Apply the pending migrations in order, and stop at the first error. With
+ * `dry_run`, only count the documents each one would change.
+ */
+pub async fn run(
+    database: &Database,
+    available_collection: &Collection,
+    dry_run: bool,
+) -> Result<Vec<MigrationReport>, NearbyError> {
+    let mut reports: Vec<MigrationReport> = Vec::new();
+    for migration in pending(database).await? {
+        let documents = if dry_run {
+            match migration.change {
+                Change::RemoveDuplicates(field) => {
+                    duplicates(available_collection, &migration.filter, field)
+                        .await?
+                        .len() as i64
+                }
+                _ => {
+                    available_collection
+                        .count_documents(migration.filter.clone(), None)
+                        .await?
+                }
+            }
+        } else {
+            let documents = match migration.change {
+                Change::Update(ref update) => {
+                    available_collection
+                        .update_many(migration.filter.clone(), update.clone(), None)
+                        .await?
+                        .modified_count
+                }
+                Change::Delete => {
+                    available_collection
+                        .delete_many(migration.filter.clone(), None)
+                        .await?
+                        .deleted_count
+                }
+                Change::RemoveDuplicates(field) => {
+                    let ids = duplicates(available_collection, &migration.filter, field).await?;
+                    available_collection
+                        .delete_many(doc! {"_id": {"$in": ids}}, None)
+                        .await?
+                        .deleted_count
+                }
+            };
+            database
+                .collection(MIGRATIONS_COLLECTION)
+                .insert_one(
+                    doc! {
+                        "_id": migration.version as i64,
+                        "description": migration.description,
+                        "applied_at": Utc::now(),
+                        "documents": documents,
+                    },
+                    None,
+                )
+                .await?;
+            documents
+        };
+        reports.push(MigrationReport {
+            version: migration.version,
+            description: migration.description,
+            documents,
+        });
+    }
+    return Ok(reports);
+}
+
+/**
+ * Ids of the documents with the same `field` as a document available later.
+ */
+async fn duplicates(
+    available_collection: &Collection,
+    filter: &Document,
+    field: &str,
+) -> Result<Vec<Bson>, NearbyError> {
+    let pipeline = vec![
+        doc! {"$match": filter.clone()},
+        doc! {"$sort": {"available_until": -1}},
+        doc! {"$group": {"_id": std::format!("${}", field), "ids": {"$push": "$_id"}}},
+        doc! {"$match": {"ids.1": {"$exists": true}}},
+    ];
+    let mut cursor = available_collection.aggregate(pipeline, None).await?;
+    let mut ids: Vec<Bson> = Vec::new();
+    while let Some(group) = cursor.next().await {
+        if let Ok(group_ids) = group?.get_array("ids") {
+            ids.extend(group_ids.iter().skip(1).cloned());
+        }
+    }
+    return Ok(ids);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        let versions: Vec<u32> = migrations()
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    fn legacy_user(phone_number_hash: &str, hours: i64) -> Document {
+        return doc! {
+            "phone_number_hash": phone_number_hash,
+            "location": {"type": "Point", "coordinates": [6.0, 43.0]},
+            // In the future, so the TTL index doesn't remove it during the test :
+            "available_until": Utc::now() + Duration::hours(hours),
+            "contacts_phone_number_hash": [],
+        };
+    }
+
+    #[tokio::test]
+    async fn test_migrations_are_applied_once() {
+        // Its own database, so other tests don't see it :
+        let configuration = DatabaseConfiguration {
+            database_name: String::from("nearby_test_migrations"),
+            ..DatabaseConfiguration::default()
+        };
+        let database = connect(&configuration).await.expect("Can't connect to DB");
+        database.drop(None).await.expect("Can't clean DB");
+        let available = database.collection(&configuration.collection_name);
+        available
+            .insert_many(
+                vec![
+                    legacy_user("v1:peppa", 1),
+                    legacy_user("v1:rebecca", 1),
+                    legacy_user("v1:rebecca", 2),
+                ],
+                None,
+            )
+            .await
+            .expect("Can't add users");
+
+        let dry_run = run(&database, &available, true)
+            .await
+            .expect("Can't dry run");
+        assert_eq!(dry_run.len(), migrations().len());
+        assert_eq!(dry_run[0].documents, 3);
+        assert_eq!(dry_run[2].documents, 1);
+        assert_eq!(
+            pending(&database).await.expect("Can't list").len(),
+            migrations().len()
+        );
+
+        let reports = run(&database, &available, false)
+            .await
+            .expect("Can't migrate");
+        let documents: Vec<i64> = reports.iter().map(|report| report.documents).collect();
+        assert_eq!(documents, vec![3, 0, 1]);
+        let rebecca = available
+            .find_one(doc! {"phone_number_hash": "v1:rebecca"}, None)
+            .await
+            .expect("Can't find user")
+            .expect("Rebecca was removed");
+        assert_eq!(
+            rebecca.get_str("location_precision").expect("No precision"),
+            "approximate"
+        );
+        // The latest availability is kept :
+        let available_until = rebecca.get_datetime("available_until").expect("No date");
+        assert!(*available_until > Utc::now() + Duration::minutes(90));
+        assert!(run(&database, &available, false)
+            .await
+            .expect("Can't migrate")
+            .is_empty());
+        crate::database::schema::ensure_schema(&database, &configuration.collection_name)
+            .await
+            .expect("Can't create the unique index");
+    }
+}
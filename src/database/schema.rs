@@ -0,0 +1,186 @@
+use crate::error::NearbyError;
+use crate::notification::notified_pairs::NOTIFIED_PAIRS_COLLECTION;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Database,
+};
+
+/**
+ * Create the available users collection, its validator and its indexes if they
+ * don't exist yet, or update them. It is idempotent, so it runs at each start :
+ * without the `2dsphere` index, nearby searches fail.
+ * `mongo/initialize_nearby_collection.js` is no longer needed.
+ */
+pub async fn ensure_schema(database: &Database, collection_name: &str) -> Result<(), NearbyError> {
+    let existing = database
+        .list_collection_names(doc! {"name": collection_name})
+        .await
+        .map_err(failed(std::format!(
+            "list the collections of {}",
+            database.name()
+        )))?;
+    if existing.is_empty() {
+        database
+            .run_command(
+                doc! {
+                    "create": collection_name,
+                    "validator": validator(),
+                    "validationLevel": "moderate",
+                },
+                None,
+            )
+            .await
+            .map_err(failed(std::format!(
+                "create the {} collection",
+                collection_name
+            )))?;
+    } else {
+        database
+            .run_command(
+                doc! {
+                    "collMod": collection_name,
+                    "validator": validator(),
+                    "validationLevel": "moderate",
+                },
+                None,
+            )
+            .await
+            .map_err(failed(std::format!(
+                "update the validator of {}",
+                collection_name
+            )))?;
+    }
+    database
+        .run_command(
+            doc! {"createIndexes": collection_name, "indexes": indexes()},
+            None,
+        )
+        .await
+        .map_err(failed(std::format!(
+            "create the indexes of {}",
+            collection_name
+        )))?;
+    // Pairs are notified again once `until` is past :
+    database
+        .run_command(
+            doc! {
+                "createIndexes": NOTIFIED_PAIRS_COLLECTION,
+                "indexes": [{"key": {"until": 1}, "name": "until_ttl", "expireAfterSeconds": 0}],
+            },
+            None,
+        )
+        .await
+        .map_err(failed(std::format!(
+            "create the indexes of {}",
+            NOTIFIED_PAIRS_COLLECTION
+        )))?;
+    return Ok(());
+}
+
+/**
+ * What `StoredUser` documents look like.
+ */
+fn validator() -> Document {
+    return doc! {
+        "$jsonSchema": {
+            "bsonType": "object",
+            "required": [
+                "phone_number_hash",
+                "location",
+                "available_until",
+                "contacts_phone_number_hash",
+            ],
+            "properties": {
+                "phone_number_hash": {"bsonType": "string"},
+                "location": {
+                    "bsonType": "object",
+                    "required": ["type", "coordinates"],
+                    "properties": {
+                        "type": {"enum": ["Point"]},
+                        "coordinates": {
+                            "bsonType": "array",
+                            "minItems": 2,
+                            "maxItems": 2,
+                            "items": {"bsonType": "double"},
+                        },
+                    },
+                },
+                "available_until": {"bsonType": "date"},
+                "contacts_phone_number_hash": {
+                    "bsonType": "array",
+                    "items": {"bsonType": "string"},
+                },
+                "location_precision": {"enum": ["exact", "approximate"]},
+                "status_message": {"bsonType": "string"},
+                "updated_at": {"bsonType": "date"},
+            },
+        },
+    };
+}
+
+/**
+ * Names are the default ones for the indexes created by the old script, or
+ * MongoDB would refuse to create them again.
+ */
+fn indexes() -> Vec<Bson> {
+    return vec![
+        // For $geoNear :
+        Bson::from(doc! {"key": {"location": "2dsphere"}, "name": "location_2dsphere"}),
+        // Users that picked us :
+        Bson::from(doc! {
+            "key": {"contacts_phone_number_hash": 1},
+            "name": "contacts_phone_number_hash_1",
+        }),
+        // Each user is stored once :
+        Bson::from(doc! {
+            "key": {"phone_number_hash": 1},
+            "name": "phone_number_hash_unique",
+            "unique": true,
+        }),
+        // MongoDB removes users itself once `available_until` is past :
+        Bson::from(doc! {
+            "key": {"available_until": 1},
+            "name": "available_until_ttl",
+            "expireAfterSeconds": 0,
+        }),
+    ];
+}
+
+fn failed(what: String) -> impl FnOnce(mongodb::error::Error) -> NearbyError {
+    return move |err| NearbyError::Internal(std::format!("Can't {} : {}", what, err));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::documents::{to_document, StoredUser};
+    use crate::models::user;
+    use chrono::{DateTime, Utc};
+
+    #[test]
+    fn test_stored_users_have_the_required_fields() {
+        let user = user::User {
+            phone_number_hash: String::from("v1:peppa"),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: DateTime::from(Utc::now()),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![],
+        };
+        let document = to_document(&StoredUser::from(&user)).expect("Can't serialize user");
+        let schema = validator();
+        let required = schema
+            .get_document("$jsonSchema")
+            .and_then(|schema| schema.get_array("required"))
+            .expect("No required fields");
+        for field in required {
+            let field = field.as_str().expect("Fields are strings");
+            assert!(document.contains_key(field), "{} is not stored", field);
+        }
+        assert!(std::matches!(
+            document.get("available_until"),
+            Some(Bson::DateTime(_))
+        ));
+    }
+}
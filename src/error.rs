@@ -0,0 +1,234 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use mongodb::{bson, error::ErrorKind, error::WriteFailure};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// MongoDB code of a duplicate key error.
+const DUPLICATE_KEY_CODE: i32 = 11000;
+/// MongoDB code of an operation that exceeded its time limit.
+const MAX_TIME_EXPIRED_CODE: i32 = 50;
+
+/**
+ * Every error the server can return. Each one has a stable `code`, sent to
+ * clients in the JSON body, so they can branch on it without parsing messages.
+ */
+#[derive(Debug)]
+pub enum NearbyError {
+    /// We can't reach the database.
+    Connection(String),
+    /// The database didn't answer in time.
+    Timeout(String),
+    /// A document in the database doesn't have the expected shape.
+    BsonDecode(String),
+    /// The client sent something we refuse.
+    Validation(String),
+    /// Same as `Validation`, but we know which fields are wrong.
+    InvalidFields(Vec<FieldError>),
+    /// No token, or a token we can't trust.
+    Unauthorized(String),
+    /// The token is valid, but doesn't allow this request.
+    Forbidden(String),
+    NotFound(String),
+    /// The request is in conflict with the current state of the data.
+    Conflict(String),
+    /// The client sends too many requests, it must wait before sending more.
+    TooManyRequests(String),
+    /// Anything else, that the client can't do anything about.
+    Internal(String),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/**
+ * JSON body of all error responses.
+ */
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: String::from(field),
+            message: String::from(message),
+        }
+    }
+}
+
+impl NearbyError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            NearbyError::Connection(_) => "CONNECTION_FAILURE",
+            NearbyError::Timeout(_) => "TIMEOUT",
+            NearbyError::BsonDecode(_) => "BSON_DECODE",
+            NearbyError::Validation(_) | NearbyError::InvalidFields(_) => "VALIDATION",
+            NearbyError::Unauthorized(_) => "UNAUTHORIZED",
+            NearbyError::Forbidden(_) => "FORBIDDEN",
+            NearbyError::NotFound(_) => "NOT_FOUND",
+            NearbyError::Conflict(_) => "CONFLICT",
+            NearbyError::TooManyRequests(_) => "TOO_MANY_REQUESTS",
+            NearbyError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            NearbyError::InvalidFields(_) => "Some fields are invalid",
+            NearbyError::Connection(message)
+            | NearbyError::Timeout(message)
+            | NearbyError::BsonDecode(message)
+            | NearbyError::Validation(message)
+            | NearbyError::Unauthorized(message)
+            | NearbyError::Forbidden(message)
+            | NearbyError::NotFound(message)
+            | NearbyError::Conflict(message)
+            | NearbyError::TooManyRequests(message)
+            | NearbyError::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for NearbyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} : {}", self.code(), self.message())
+    }
+}
+
+impl ResponseError for NearbyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            NearbyError::Connection(_) => StatusCode::SERVICE_UNAVAILABLE,
+            NearbyError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            NearbyError::BsonDecode(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            NearbyError::Validation(_) | NearbyError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            NearbyError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            NearbyError::Forbidden(_) => StatusCode::FORBIDDEN,
+            NearbyError::NotFound(_) => StatusCode::NOT_FOUND,
+            NearbyError::Conflict(_) => StatusCode::CONFLICT,
+            NearbyError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            NearbyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            code: String::from(self.code()),
+            message: String::from(self.message()),
+            fields: match self {
+                NearbyError::InvalidFields(fields) => fields.clone(),
+                _ => Vec::new(),
+            },
+        })
+    }
+}
+
+impl From<mongodb::error::Error> for NearbyError {
+    fn from(db_error: mongodb::error::Error) -> Self {
+        let message = std::format!("Database Error : {}", db_error);
+        return match db_error.kind.as_ref() {
+            ErrorKind::ServerSelectionError { .. }
+            | ErrorKind::ConnectionPoolClearedError { .. }
+            | ErrorKind::AuthenticationError { .. }
+            | ErrorKind::DnsResolve(_)
+            | ErrorKind::NoDnsResults(_) => NearbyError::Connection(message),
+            ErrorKind::Io(io_error) if io_error.kind() == std::io::ErrorKind::TimedOut => {
+                NearbyError::Timeout(message)
+            }
+            ErrorKind::Io(_) => NearbyError::Connection(message),
+            ErrorKind::TokioTimeoutElapsed(_) | ErrorKind::WaitQueueTimeoutError { .. } => {
+                NearbyError::Timeout(message)
+            }
+            ErrorKind::BsonDecode(_) => NearbyError::BsonDecode(message),
+            ErrorKind::CommandError(command_error) => match command_error.code {
+                DUPLICATE_KEY_CODE => NearbyError::Conflict(message),
+                MAX_TIME_EXPIRED_CODE => NearbyError::Timeout(message),
+                _ => NearbyError::Internal(message),
+            },
+            ErrorKind::WriteError(WriteFailure::WriteError(write_error))
+                if write_error.code == DUPLICATE_KEY_CODE =>
+            {
+                NearbyError::Conflict(message)
+            }
+            _ => NearbyError::Internal(message),
+        };
+    }
+}
+
+impl From<bson::de::Error> for NearbyError {
+    fn from(bson_error: bson::de::Error) -> Self {
+        return NearbyError::BsonDecode(std::format!("BSON Error : {}", bson_error));
+    }
+}
+
+impl From<bson::ser::Error> for NearbyError {
+    fn from(bson_error: bson::ser::Error) -> Self {
+        return NearbyError::Internal(std::format!("BSON Error : {}", bson_error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::{Body, ResponseBody};
+
+    #[test]
+    fn test_errors_are_sent_as_json_with_their_code() {
+        let error = NearbyError::Validation(String::from("max_distance_m is too big"));
+        let mut response = error.error_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = match response.take_body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => bytes,
+            _ => panic!("Error body should be bytes"),
+        };
+        let body: ErrorBody = serde_json::from_slice(&body).expect("Body is not an ErrorBody");
+        assert_eq!(body.code, "VALIDATION");
+        assert_eq!(body.message, "max_distance_m is too big");
+    }
+
+    #[test]
+    fn test_invalid_fields_are_listed() {
+        let error = NearbyError::InvalidFields(vec![FieldError::new(
+            "latitude",
+            "must be between -90 and 90",
+        )]);
+        let mut response = error.error_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = match response.take_body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => bytes,
+            _ => panic!("Error body should be bytes"),
+        };
+        let body: ErrorBody = serde_json::from_slice(&body).expect("Body is not an ErrorBody");
+        assert_eq!(body.code, "VALIDATION");
+        assert_eq!(
+            body.fields,
+            vec![FieldError::new("latitude", "must be between -90 and 90")]
+        );
+    }
+
+    #[test]
+    fn test_mongo_errors_are_mapped() {
+        let timeout =
+            mongodb::error::Error::from(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert!(matches!(
+            NearbyError::from(timeout),
+            NearbyError::Timeout(_)
+        ));
+
+        let refused = mongodb::error::Error::from(std::io::Error::from(
+            std::io::ErrorKind::ConnectionRefused,
+        ));
+        let refused = NearbyError::from(refused);
+        assert!(matches!(refused, NearbyError::Connection(_)));
+        assert_eq!(refused.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
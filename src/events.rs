@@ -0,0 +1,4 @@
+pub mod change_stream_watcher;
+pub mod event_bus;
+pub mod match_broker;
+pub mod publishing_store;
@@ -0,0 +1,193 @@
+use crate::database::documents::{self, StoredUser};
+use crate::error::NearbyError;
+use crate::events::event_bus::{AvailabilityEvent, EventBus};
+use crate::models::user;
+use actix::prelude::*;
+use core::time::Duration;
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::{AggregateOptions, FindOptions},
+    Collection,
+};
+use std::collections::HashMap;
+
+/// How long to wait before opening the change stream again after an error.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/**
+ * Follows the available users collection with a MongoDB change stream, and
+ * publishes inserts, replacements and deletes on the bus. Unlike the
+ * `PublishingStore`, it also sees the writes of the other instances.
+ */
+pub struct ChangeStreamWatcher {
+    collection: Collection,
+    bus: EventBus,
+}
+
+impl ChangeStreamWatcher {
+    pub fn new(collection: Collection, bus: EventBus) -> Self {
+        ChangeStreamWatcher { collection, bus }
+    }
+
+    /**
+     * Never returns : when the stream fails, it is resumed after the last
+     * change we published.
+     */
+    async fn watch(collection: Collection, bus: EventBus) {
+        let mut resume_token: Option<Document> = None;
+        // Deletes only give the _id of the removed document :
+        let mut hashes_by_id: HashMap<String, String> = HashMap::new();
+        loop {
+            let res = ChangeStreamWatcher::follow(
+                &collection,
+                &bus,
+                &mut resume_token,
+                &mut hashes_by_id,
+            )
+            .await;
+            if let Err(err) = res {
+                println!("Error when watching changes : {}", err);
+            }
+            actix_rt::time::delay_for(RETRY_INTERVAL).await;
+        }
+    }
+
+    async fn follow(
+        collection: &Collection,
+        bus: &EventBus,
+        resume_token: &mut Option<Document>,
+        hashes_by_id: &mut HashMap<String, String>,
+    ) -> Result<(), NearbyError> {
+        let mut change_stream = doc! {"fullDocument": "updateLookup"};
+        if let Some(token) = resume_token.clone() {
+            change_stream.insert("resumeAfter", token);
+        }
+        let options = AggregateOptions::builder()
+            .max_await_time(Duration::from_secs(10))
+            .build();
+        let mut changes = collection
+            .aggregate(vec![doc! {"$changeStream": change_stream}], options)
+            .await?;
+
+        // The stream is open, users stored before it are read now :
+        let options = FindOptions::builder()
+            .projection(doc! {"phone_number_hash": 1})
+            .build();
+        let mut documents = collection.find(doc! {}, options).await?;
+        while let Some(document) = documents.next().await {
+            let document = document?;
+            if let (Some(id), Ok(phone_number_hash)) =
+                (document.get("_id"), document.get_str("phone_number_hash"))
+            {
+                hashes_by_id.insert(id.to_string(), String::from(phone_number_hash));
+            }
+        }
+
+        println!("Watching changes of available users");
+        while let Some(change) = changes.next().await {
+            let change = change?;
+            if let Some(event) = to_event(&change, hashes_by_id) {
+                bus.publish(event);
+            }
+            match change.get("operationType").and_then(Bson::as_str) {
+                // The collection was dropped or renamed, start over :
+                Some("invalidate") => *resume_token = None,
+                _ => *resume_token = change.get_document("_id").ok().cloned(),
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl Actor for ChangeStreamWatcher {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        println!("Starting Change Stream Watcher");
+        ctx.spawn(
+            ChangeStreamWatcher::watch(self.collection.clone(), self.bus.clone()).into_actor(self),
+        );
+    }
+}
+
+/**
+ * Turn a change event into an availability event, `None` for changes that are
+ * not about available users.
+ */
+fn to_event(
+    change: &Document,
+    hashes_by_id: &mut HashMap<String, String>,
+) -> Option<AvailabilityEvent> {
+    let id = change
+        .get_document("documentKey")
+        .ok()
+        .and_then(|key| key.get("_id"))
+        .map(Bson::to_string)?;
+    match change.get("operationType").and_then(Bson::as_str)? {
+        "insert" | "replace" | "update" => {
+            // Null when the document was deleted before the lookup :
+            let document = change.get_document("fullDocument").ok()?;
+            match documents::from_document::<StoredUser>(document.clone()) {
+                Ok(stored) => {
+                    let user = user::User::from(stored);
+                    hashes_by_id.insert(id, user.phone_number_hash.clone());
+                    Some(AvailabilityEvent::Available(user))
+                }
+                Err(err) => {
+                    println!("Can't read changed user : {}", err);
+                    None
+                }
+            }
+        }
+        "delete" => hashes_by_id
+            .remove(&id)
+            .map(|phone_number_hash| AvailabilityEvent::Unavailable { phone_number_hash }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use mongodb::bson::oid::ObjectId;
+
+    #[test]
+    fn test_changes_become_availability_events() {
+        let id = ObjectId::new();
+        let user = user::User {
+            phone_number_hash: String::from("v1:peppa"),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: DateTime::from(Utc::now()),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![String::from("v1:rebecca")],
+        };
+        let mut hashes_by_id = HashMap::new();
+
+        let inserted = doc! {
+            "operationType": "insert",
+            "documentKey": {"_id": id.clone()},
+            "fullDocument": documents::to_document(&StoredUser::from(&user))
+                .expect("Can't serialize user"),
+        };
+        match to_event(&inserted, &mut hashes_by_id) {
+            Some(AvailabilityEvent::Available(available)) => {
+                assert_eq!(available.phone_number_hash, "v1:peppa");
+                assert_eq!(available.contacts_phone_number_hash, vec!["v1:rebecca"]);
+            }
+            _ => panic!("Inserts must make users available"),
+        }
+
+        let deleted = doc! {"operationType": "delete", "documentKey": {"_id": id}};
+        match to_event(&deleted, &mut hashes_by_id) {
+            Some(AvailabilityEvent::Unavailable { phone_number_hash }) => {
+                assert_eq!(phone_number_hash, "v1:peppa")
+            }
+            _ => panic!("Deletes must make users unavailable"),
+        }
+        // We don't know who it was anymore :
+        assert!(to_event(&deleted, &mut hashes_by_id).is_none());
+    }
+}
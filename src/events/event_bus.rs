@@ -0,0 +1,43 @@
+use crate::models::user;
+use futures::channel::mpsc;
+use std::sync::{Arc, Mutex};
+
+/**
+ * A change in available users, as seen by the store.
+ */
+#[derive(Clone)]
+pub enum AvailabilityEvent {
+    /// The user was stored, for the first time or again.
+    Available(user::User),
+    /// The user was removed, on request or because it is no longer available.
+    Unavailable { phone_number_hash: String },
+}
+
+/**
+ * Sends each published event to all the subscribers. Subscribers that dropped
+ * their receiver are forgotten on the next publication.
+ */
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<AvailabilityEvent>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<AvailabilityEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers
+            .lock()
+            .expect("Event bus is poisoned")
+            .push(sender);
+        return receiver;
+    }
+
+    pub fn publish(&self, event: AvailabilityEvent) {
+        let mut subscribers = self.subscribers.lock().expect("Event bus is poisoned");
+        subscribers.retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
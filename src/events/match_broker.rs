@@ -0,0 +1,521 @@
+use crate::clock::{SharedClock, SystemClock};
+use crate::configuration::PrivacyConfiguration;
+use crate::database::in_memory_store::{bearing_deg, haversine_distance_m};
+use crate::events::event_bus::{AvailabilityEvent, EventBus};
+use crate::models::user;
+use crate::privacy;
+use actix::prelude::*;
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// How often contacts whose availability is over are removed.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchEventKind {
+    /// A contact became available nearby.
+    Available,
+    /// A contact nearby posted a new location.
+    Moved,
+    /// A contact is no longer available, or no longer nearby.
+    Unavailable,
+}
+
+/**
+ * What subscribers receive, the contact is given like in nearby searches.
+ */
+#[derive(Clone, Deserialize, Message, Serialize)]
+#[rtype(result = "()")]
+pub struct MatchEvent {
+    pub event: MatchEventKind,
+    #[serde(flatten)]
+    pub contact: user::LocalizedUser,
+}
+
+/**
+ * Start sending the match events of a user to `recipient`, until `Unsubscribe`.
+ */
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    /// Chosen by the subscriber, to unsubscribe.
+    pub id: usize,
+    /// Keyed hashes of the subscriber, with all the keys.
+    pub phone_number_hashes: Vec<String>,
+    /// Where the subscriber declared to be, if it is available.
+    pub location: Option<(f64, f64)>,
+    pub recipient: Recipient<MatchEvent>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unsubscribe {
+    pub id: usize,
+}
+
+struct Subscriber {
+    phone_number_hashes: Vec<String>,
+    location: Option<(f64, f64)>,
+    /// Contacts that picked this subscriber, as they last posted, so they can
+    /// be checked again when the subscriber moves.
+    picked_by: HashMap<String, user::User>,
+    /// Contacts this subscriber was told are available nearby.
+    visible: HashSet<String>,
+    recipient: Recipient<MatchEvent>,
+}
+
+/**
+ * Turns availability events from the bus into match events for each subscriber :
+ * a contact is a match when it picked the subscriber, and is less than
+ * `radius_m` away from the subscriber's declared location.
+ * Without change streams, nothing tells when MongoDB removes expired users, so
+ * the broker also checks the `available_until` of contacts itself. With change
+ * streams the removal comes twice, and the second one changes nothing.
+ */
+pub struct MatchBroker {
+    bus: EventBus,
+    radius_m: f32,
+    privacy: PrivacyConfiguration,
+    subscribers: HashMap<usize, Subscriber>,
+    clock: SharedClock,
+    check_interval: Duration,
+}
+
+impl MatchBroker {
+    pub fn new(bus: EventBus, radius_m: f32, privacy: PrivacyConfiguration) -> Self {
+        MatchBroker {
+            bus,
+            radius_m,
+            privacy,
+            subscribers: HashMap::new(),
+            clock: SystemClock::shared(),
+            check_interval: EXPIRY_CHECK_INTERVAL,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: SharedClock, check_interval: Duration) -> Self {
+        self.clock = clock;
+        self.check_interval = check_interval;
+        return self;
+    }
+
+    fn user_available(&mut self, user: &user::User) {
+        let now: DateTime<FixedOffset> = DateTime::from(self.clock.now());
+        for subscriber in self.subscribers.values_mut() {
+            if subscriber
+                .phone_number_hashes
+                .contains(&user.phone_number_hash)
+            {
+                // The subscriber moved, some contacts may now be closer or too far :
+                subscriber.location = Some((user.latitude, user.longitude));
+                let contacts: Vec<user::User> = subscriber.picked_by.values().cloned().collect();
+                for contact in contacts.iter() {
+                    update_match(
+                        subscriber,
+                        contact,
+                        self.radius_m,
+                        &self.privacy,
+                        now,
+                        false,
+                    );
+                }
+                continue;
+            }
+            let picked = user
+                .contacts_phone_number_hash
+                .iter()
+                .any(|contact| subscriber.phone_number_hashes.contains(contact));
+            if picked {
+                subscriber
+                    .picked_by
+                    .insert(user.phone_number_hash.clone(), user.clone());
+            } else {
+                subscriber.picked_by.remove(&user.phone_number_hash);
+            }
+            update_match(subscriber, user, self.radius_m, &self.privacy, now, true);
+        }
+    }
+
+    fn user_unavailable(&mut self, phone_number_hash: &str) {
+        for subscriber in self.subscribers.values_mut() {
+            if subscriber
+                .phone_number_hashes
+                .iter()
+                .any(|hash| hash == phone_number_hash)
+            {
+                subscriber.location = None;
+            }
+            subscriber.picked_by.remove(phone_number_hash);
+            // Already removed when it expired :
+            if subscriber.visible.remove(phone_number_hash) {
+                send_unavailable(subscriber, phone_number_hash);
+            }
+        }
+    }
+
+    fn remove_expired_contacts(&mut self) {
+        let now: DateTime<FixedOffset> = DateTime::from(self.clock.now());
+        for subscriber in self.subscribers.values_mut() {
+            let expired: Vec<String> = subscriber
+                .picked_by
+                .values()
+                .filter(|contact| contact.available_until <= now)
+                .map(|contact| contact.phone_number_hash.clone())
+                .collect();
+            for phone_number_hash in expired.iter() {
+                subscriber.picked_by.remove(phone_number_hash);
+                if subscriber.visible.remove(phone_number_hash) {
+                    send_unavailable(subscriber, phone_number_hash);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Tell the subscriber if `contact` became a match, or is no longer one. When
+ * the contact posted a new location and is still a match, it `moved`.
+ */
+fn update_match(
+    subscriber: &mut Subscriber,
+    contact: &user::User,
+    radius_m: f32,
+    privacy: &PrivacyConfiguration,
+    now: DateTime<FixedOffset>,
+    contact_moved: bool,
+) {
+    let picked = subscriber
+        .picked_by
+        .contains_key(&contact.phone_number_hash);
+    let distance = subscriber.location.map(|(latitude, longitude)| {
+        haversine_distance_m(latitude, longitude, contact.latitude, contact.longitude) as f32
+    });
+    let is_match = picked
+        && contact.available_until > now
+        && distance.is_some_and(|distance| distance <= radius_m);
+    let was_visible = subscriber.visible.contains(&contact.phone_number_hash);
+    let event = match (is_match, was_visible) {
+        (true, false) => MatchEventKind::Available,
+        (true, true) if contact_moved => MatchEventKind::Moved,
+        (false, true) => MatchEventKind::Unavailable,
+        _ => return,
+    };
+    let mut localized = user::LocalizedUser {
+        phone_number_hash: contact.phone_number_hash.clone(),
+        distance: None,
+        distance_bucket: None,
+        location_precision: contact.location_precision,
+        available_until: None,
+        status_message: None,
+        bearing: None,
+        direction: None,
+        updated_at: None,
+    };
+    if is_match {
+        subscriber.visible.insert(contact.phone_number_hash.clone());
+        localized.distance = distance;
+        localized.available_until = Some(contact.available_until);
+        localized.status_message = contact.status_message.clone();
+        localized.bearing = subscriber.location.and_then(|(latitude, longitude)| {
+            bearing_deg(latitude, longitude, contact.latitude, contact.longitude)
+        });
+        privacy::blur_result(&mut localized, privacy);
+    } else {
+        subscriber.visible.remove(&contact.phone_number_hash);
+    }
+    let _ = subscriber.recipient.do_send(MatchEvent {
+        event,
+        contact: localized,
+    });
+}
+
+fn send_unavailable(subscriber: &Subscriber, phone_number_hash: &str) {
+    let _ = subscriber.recipient.do_send(MatchEvent {
+        event: MatchEventKind::Unavailable,
+        contact: user::LocalizedUser {
+            phone_number_hash: String::from(phone_number_hash),
+            distance: None,
+            distance_bucket: None,
+            location_precision: user::LocationPrecision::default(),
+            available_until: None,
+            status_message: None,
+            bearing: None,
+            direction: None,
+            updated_at: None,
+        },
+    });
+}
+
+impl Actor for MatchBroker {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        println!("Starting Match Broker");
+        ctx.add_stream(self.bus.subscribe());
+        ctx.run_interval(self.check_interval, |this, _| {
+            this.remove_expired_contacts();
+        });
+    }
+}
+
+impl StreamHandler<AvailabilityEvent> for MatchBroker {
+    fn handle(&mut self, event: AvailabilityEvent, _: &mut Context<Self>) {
+        match event {
+            AvailabilityEvent::Available(user) => self.user_available(&user),
+            AvailabilityEvent::Unavailable { phone_number_hash } => {
+                self.user_unavailable(&phone_number_hash)
+            }
+        }
+    }
+}
+
+impl Handler<Subscribe> for MatchBroker {
+    type Result = ();
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        self.subscribers.insert(
+            msg.id,
+            Subscriber {
+                phone_number_hashes: msg.phone_number_hashes,
+                location: msg.location,
+                picked_by: HashMap::new(),
+                visible: HashSet::new(),
+                recipient: msg.recipient,
+            },
+        );
+    }
+}
+
+impl Handler<Unsubscribe> for MatchBroker {
+    type Result = ();
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Context<Self>) {
+        self.subscribers.remove(&msg.id);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::clock::{Clock, FixedClock};
+    use chrono::Utc;
+    use std::sync::{Arc, Mutex};
+
+    /**
+     * Keeps the events it receives.
+     */
+    pub struct EventCollector {
+        pub events: Arc<Mutex<Vec<MatchEvent>>>,
+    }
+
+    impl Actor for EventCollector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<MatchEvent> for EventCollector {
+        type Result = ();
+        fn handle(&mut self, msg: MatchEvent, _: &mut Context<Self>) {
+            self.events.lock().expect("Collector is poisoned").push(msg);
+        }
+    }
+
+    /**
+     * Events go through the bus and the broker mailbox, give them some time.
+     */
+    pub async fn wait_for_events(
+        events: &Arc<Mutex<Vec<MatchEvent>>>,
+        count: usize,
+    ) -> Vec<MatchEvent> {
+        for _ in 0..100 {
+            if events.lock().expect("Collector is poisoned").len() >= count {
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        return events.lock().expect("Collector is poisoned").clone();
+    }
+
+    fn available(phone_number_hash: &str, latitude: f64, contacts: &[&str]) -> user::User {
+        user::User {
+            phone_number_hash: String::from(phone_number_hash),
+            latitude,
+            longitude: 6.0,
+            available_until: DateTime::from(Utc::now() + chrono::Duration::hours(1)),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: contacts.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_subscribers_follow_their_contacts() {
+        let bus = EventBus::new();
+        let broker =
+            MatchBroker::new(bus.clone(), 10_000.0, PrivacyConfiguration::default()).start();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let collector = EventCollector {
+            events: events.clone(),
+        }
+        .start();
+        broker
+            .send(Subscribe {
+                id: 1,
+                phone_number_hashes: vec![String::from("Peppa")],
+                location: Some((43.0, 6.0)),
+                recipient: collector.recipient(),
+            })
+            .await
+            .expect("Broker is dead");
+
+        // Suzy didn't pick Peppa, Rebecca did :
+        bus.publish(AvailabilityEvent::Available(available("Suzy", 43.0, &[])));
+        bus.publish(AvailabilityEvent::Available(available(
+            "Rebecca",
+            43.001,
+            &["Peppa"],
+        )));
+        bus.publish(AvailabilityEvent::Available(available(
+            "Rebecca",
+            43.002,
+            &["Peppa"],
+        )));
+        // Too far :
+        bus.publish(AvailabilityEvent::Available(available(
+            "Rebecca",
+            44.0,
+            &["Peppa"],
+        )));
+        bus.publish(AvailabilityEvent::Available(available(
+            "Rebecca",
+            43.0,
+            &["Peppa"],
+        )));
+        bus.publish(AvailabilityEvent::Unavailable {
+            phone_number_hash: String::from("Rebecca"),
+        });
+
+        let events = wait_for_events(&events, 5).await;
+        let kinds: Vec<MatchEventKind> = events.iter().map(|event| event.event).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                MatchEventKind::Available,
+                MatchEventKind::Moved,
+                MatchEventKind::Unavailable,
+                MatchEventKind::Available,
+                MatchEventKind::Unavailable,
+            ]
+        );
+        assert!(events
+            .iter()
+            .all(|event| event.contact.phone_number_hash == "Rebecca"));
+        assert_eq!(events[1].contact.distance_bucket.as_deref(), Some("<500 m"));
+        assert_eq!(events[1].contact.direction.as_deref(), Some("north"));
+        assert!(events[1].contact.available_until.is_some());
+        assert!(events[2].contact.available_until.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_expired_contacts_are_unavailable() {
+        let bus = EventBus::new();
+        let clock = FixedClock::at("2021-06-01T12:00:00+00:00");
+        let broker = MatchBroker::new(bus.clone(), 10_000.0, PrivacyConfiguration::default())
+            .with_clock(clock.clone(), Duration::from_millis(10))
+            .start();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let collector = EventCollector {
+            events: events.clone(),
+        }
+        .start();
+        broker
+            .send(Subscribe {
+                id: 1,
+                phone_number_hashes: vec![String::from("Peppa")],
+                location: Some((43.0, 6.0)),
+                recipient: collector.recipient(),
+            })
+            .await
+            .expect("Broker is dead");
+
+        let mut rebecca = available("Rebecca", 43.001, &["Peppa"]);
+        rebecca.available_until = DateTime::from(clock.now() + chrono::Duration::hours(1));
+        bus.publish(AvailabilityEvent::Available(rebecca));
+        assert_eq!(wait_for_events(&events, 1).await.len(), 1);
+
+        // Without change streams, nothing tells when MongoDB removes Rebecca :
+        clock.advance(chrono::Duration::hours(1));
+        assert_eq!(wait_for_events(&events, 2).await.len(), 2);
+        // With change streams, the delete of the TTL index comes afterwards :
+        bus.publish(AvailabilityEvent::Unavailable {
+            phone_number_hash: String::from("Rebecca"),
+        });
+        let mut suzy = available("Suzy", 43.0, &["Peppa"]);
+        suzy.available_until = DateTime::from(clock.now() + chrono::Duration::hours(1));
+        bus.publish(AvailabilityEvent::Available(suzy));
+
+        let events = wait_for_events(&events, 3).await;
+        let received: Vec<(MatchEventKind, &str)> = events
+            .iter()
+            .map(|event| (event.event, event.contact.phone_number_hash.as_str()))
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                (MatchEventKind::Available, "Rebecca"),
+                (MatchEventKind::Unavailable, "Rebecca"),
+                (MatchEventKind::Available, "Suzy"),
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_matches_follow_the_subscriber() {
+        let bus = EventBus::new();
+        let broker =
+            MatchBroker::new(bus.clone(), 10_000.0, PrivacyConfiguration::default()).start();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let collector = EventCollector {
+            events: events.clone(),
+        }
+        .start();
+        broker
+            .send(Subscribe {
+                id: 1,
+                phone_number_hashes: vec![String::from("Peppa")],
+                location: Some((43.0, 6.0)),
+                recipient: collector.recipient(),
+            })
+            .await
+            .expect("Broker is dead");
+
+        bus.publish(AvailabilityEvent::Available(available(
+            "Rebecca",
+            43.001,
+            &["Peppa"],
+        )));
+        // Too far, until Peppa joins her :
+        bus.publish(AvailabilityEvent::Available(available(
+            "Suzy",
+            43.5,
+            &["Peppa"],
+        )));
+        bus.publish(AvailabilityEvent::Available(available("Peppa", 43.5, &[])));
+
+        let events = wait_for_events(&events, 3).await;
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].event, MatchEventKind::Available);
+        let mut received: Vec<(MatchEventKind, &str)> = events[1..]
+            .iter()
+            .map(|event| (event.event, event.contact.phone_number_hash.as_str()))
+            .collect();
+        received.sort_by_key(|(_, phone_number_hash)| *phone_number_hash);
+        assert_eq!(
+            received,
+            vec![
+                (MatchEventKind::Unavailable, "Rebecca"),
+                (MatchEventKind::Available, "Suzy"),
+            ]
+        );
+    }
+}
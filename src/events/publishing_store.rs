@@ -0,0 +1,89 @@
+use crate::database::availability_store::AvailabilityStore;
+use crate::database::database_interface::ReplacedOrInserted;
+use crate::error::NearbyError;
+use crate::events::event_bus::{AvailabilityEvent, EventBus};
+use crate::models::user;
+use chrono::{DateTime, FixedOffset};
+
+/**
+ * Wraps a store, and publishes an event on the bus for each successful write.
+ */
+#[derive(Clone)]
+pub struct PublishingStore<S: AvailabilityStore> {
+    inner: S,
+    bus: EventBus,
+}
+
+impl<S: AvailabilityStore> PublishingStore<S> {
+    pub fn new(inner: S, bus: EventBus) -> Self {
+        PublishingStore { inner, bus }
+    }
+}
+
+impl<S: AvailabilityStore> AvailabilityStore for PublishingStore<S> {
+    async fn set_user_available(
+        &self,
+        user: &user::User,
+    ) -> Result<ReplacedOrInserted, NearbyError> {
+        let res = self.inner.set_user_available(user).await?;
+        // Posting the same status again is not news :
+        if !std::matches!(&res, ReplacedOrInserted::Replaced(changes) if !changes.any()) {
+            self.bus.publish(AvailabilityEvent::Available(user.clone()));
+        }
+        return Ok(res);
+    }
+
+    async fn get_user(&self, phone_hash: &str) -> Result<Option<user::User>, NearbyError> {
+        return self.inner.get_user(phone_hash).await;
+    }
+
+    async fn get_contacts_available_nearby(
+        &self,
+        my_phone_hash: &str,
+        my_latitude: f64,
+        my_longitude: f64,
+        max_distance_m: f32,
+        limit: Option<u32>,
+        mutual: bool,
+    ) -> Result<Vec<user::LocalizedUser>, NearbyError> {
+        return self
+            .inner
+            .get_contacts_available_nearby(
+                my_phone_hash,
+                my_latitude,
+                my_longitude,
+                max_distance_m,
+                limit,
+                mutual,
+            )
+            .await;
+    }
+
+    async fn remove_user(&self, phone_hash: &str) -> Result<bool, NearbyError> {
+        let removed = self.inner.remove_user(phone_hash).await?;
+        if removed {
+            self.bus.publish(AvailabilityEvent::Unavailable {
+                phone_number_hash: String::from(phone_hash),
+            });
+        }
+        return Ok(removed);
+    }
+
+    async fn remove_available_until(
+        &self,
+        date_time: DateTime<FixedOffset>,
+    ) -> Result<Vec<String>, NearbyError> {
+        let removed = self.inner.remove_available_until(date_time).await?;
+        for phone_hash in removed.iter() {
+            self.bus.publish(AvailabilityEvent::Unavailable {
+                phone_number_hash: phone_hash.clone(),
+            });
+        }
+        return Ok(removed);
+    }
+
+    #[cfg(test)]
+    async fn clear_database(&self) -> Result<i64, NearbyError> {
+        return self.inner.clear_database().await;
+    }
+}
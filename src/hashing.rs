@@ -0,0 +1,203 @@
+use crate::configuration::{HashKeyConfiguration, HashingConfiguration};
+use crate::models::user;
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/**
+ * Phone number hashes sent by clients are plain hashes of phone numbers : anyone
+ * can find the number back by hashing all the phone numbers. So we only store
+ * HMAC of these hashes, with a secret key that never leaves the server.
+ *
+ * Keyed hashes are versioned ("v2:0af3...") so keys can be rotated :
+ *   1. Add the new key : contacts are now stored with all the keys.
+ *   2. Once all available users posted again (see `max_availability_hours`),
+ *      make it the current one : users are now stored with the new key.
+ *   3. Wait again, and remove the old key.
+ */
+#[derive(Clone)]
+pub struct PhoneHasher {
+    /// Current key first.
+    keys: Vec<HashKeyConfiguration>,
+}
+
+impl PhoneHasher {
+    pub fn new(configuration: &HashingConfiguration) -> Result<Self, String> {
+        let current = configuration
+            .keys
+            .iter()
+            .find(|key| key.version == configuration.current_version)
+            .ok_or_else(|| {
+                std::format!(
+                    "No hashing key for current version {}",
+                    configuration.current_version
+                )
+            })?;
+        let mut keys = vec![current.clone()];
+        keys.extend(
+            configuration
+                .keys
+                .iter()
+                .filter(|key| key.version != configuration.current_version)
+                .cloned(),
+        );
+        return Ok(PhoneHasher { keys });
+    }
+
+    /**
+     * A hasher with a random key, matches won't survive a restart.
+     */
+    pub fn with_random_key() -> Self {
+        let secret: [u8; 32] = rand::thread_rng().gen();
+        PhoneHasher {
+            keys: vec![HashKeyConfiguration {
+                version: 1,
+                secret: secret
+                    .iter()
+                    .map(|byte| std::format!("{:02x}", byte))
+                    .collect(),
+            }],
+        }
+    }
+
+    /**
+     * Keyed hash with the current key.
+     */
+    pub fn keyed(&self, client_hash: &str) -> String {
+        return keyed_with(&self.keys[0], client_hash);
+    }
+
+    /**
+     * Keyed hashes with all the keys, the current one first.
+     */
+    pub fn all_keyed(&self, client_hash: &str) -> Vec<String> {
+        return self
+            .keys
+            .iter()
+            .map(|key| keyed_with(key, client_hash))
+            .collect();
+    }
+
+    /**
+     * The user as it must be stored : its own hash with the current key, its
+     * contacts with all the keys.
+     */
+    pub fn keyed_user(&self, user: &user::User) -> user::User {
+        let mut keyed_user = user.clone();
+        keyed_user.phone_number_hash = self.keyed(&user.phone_number_hash);
+        keyed_user.contacts_phone_number_hash = user
+            .contacts_phone_number_hash
+            .iter()
+            .flat_map(|contact| self.all_keyed(contact))
+            .collect();
+        return keyed_user;
+    }
+
+    /**
+     * Map from keyed hashes to the contacts hashes sent by the client, so it can
+     * find who are the users returned by a nearby search.
+     */
+    pub fn keyed_contacts(&self, user: &user::User) -> HashMap<String, String> {
+        let mut keyed_contacts = HashMap::new();
+        for contact in user.contacts_phone_number_hash.iter() {
+            for keyed in self.all_keyed(contact) {
+                keyed_contacts.insert(keyed, contact.clone());
+            }
+        }
+        return keyed_contacts;
+    }
+}
+
+fn keyed_with(key: &HashKeyConfiguration, client_hash: &str) -> String {
+    // HMAC accepts keys of any size :
+    let mut mac = HmacSha256::new_varkey(key.secret.as_bytes()).expect("Invalid HMAC key");
+    mac.update(client_hash.as_bytes());
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| std::format!("{:02x}", byte))
+        .collect();
+    return std::format!("v{}:{}", key.version, digest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn key(version: u32, secret: &str) -> HashKeyConfiguration {
+        HashKeyConfiguration {
+            version,
+            secret: String::from(secret),
+        }
+    }
+
+    #[test]
+    fn test_keyed_hashes_are_versioned() {
+        let hasher = PhoneHasher::new(&HashingConfiguration {
+            current_version: 2,
+            keys: vec![key(1, "old secret"), key(2, "new secret")],
+        })
+        .expect("Can't create hasher");
+
+        let keyed = hasher.keyed("Peppa");
+        assert!(keyed.starts_with("v2:"));
+        assert_eq!(keyed.len(), 3 + 64);
+        assert_eq!(keyed, hasher.keyed("Peppa"));
+        assert_ne!(keyed, hasher.keyed("Rebecca"));
+
+        let all_keyed = hasher.all_keyed("Peppa");
+        assert_eq!(all_keyed.len(), 2);
+        assert_eq!(all_keyed[0], keyed);
+        assert!(all_keyed[1].starts_with("v1:"));
+    }
+
+    #[test]
+    fn test_matches_survive_key_rotation() {
+        let before = PhoneHasher::new(&HashingConfiguration {
+            current_version: 1,
+            keys: vec![key(1, "old secret"), key(2, "new secret")],
+        })
+        .expect("Can't create hasher");
+        let after = PhoneHasher::new(&HashingConfiguration {
+            current_version: 2,
+            keys: vec![key(1, "old secret"), key(2, "new secret")],
+        })
+        .expect("Can't create hasher");
+
+        // Rebecca posted before the current key changed, Peppa searches after :
+        let rebecca = user::User {
+            phone_number_hash: String::from("Rebecca"),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: DateTime::parse_from_rfc3339("2021-05-21T21:00:00+00:00")
+                .expect("Can't parse date"),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![String::from("Peppa")],
+        };
+        let stored = before.keyed_user(&rebecca);
+        assert!(stored
+            .contacts_phone_number_hash
+            .contains(&after.keyed("Peppa")));
+
+        let keyed_contacts = after.keyed_contacts(&rebecca);
+        assert_eq!(
+            keyed_contacts.get(&before.keyed("Peppa")),
+            Some(&String::from("Peppa"))
+        );
+    }
+
+    #[test]
+    fn test_current_key_must_exist() {
+        assert!(PhoneHasher::new(&HashingConfiguration {
+            current_version: 3,
+            keys: vec![key(1, "old secret")],
+        })
+        .is_err());
+    }
+}
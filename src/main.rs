@@ -1,34 +1,232 @@
+#![allow(clippy::needless_return)]
 use actix::prelude::*;
 use actix_web::{web, App, HttpServer};
+use core::time::Duration;
 
+mod authentication;
+mod clock;
+mod configuration;
 mod database;
+mod error;
+mod events;
+mod hashing;
 mod models;
+mod notification;
+mod privacy;
+mod rate_limiter;
 mod routes;
+mod verification;
+use authentication::{middleware::Authentication, token::TokenSigner};
+use configuration::{
+    AuthenticationConfiguration, Configuration, HashingConfiguration, StorageBackend,
+};
 use database::{
-    available_users_cleaner::AvailableUserCleaner, database_interface::DataBaseInterface,
+    availability_store::AvailabilityStore, available_users_cleaner::AvailableUserCleaner,
+    database_interface::DataBaseInterface, in_memory_store::InMemoryStore, migrations, schema,
+};
+use events::{
+    change_stream_watcher::ChangeStreamWatcher, event_bus::EventBus, match_broker::MatchBroker,
+    publishing_store::PublishingStore,
 };
-use routes::user_available;
+use hashing::PhoneHasher;
+use notification::{
+    notification_dispatcher::NotificationDispatcher, notified_pairs::NotifiedPairs,
+    push_sender::LogPushSender, push_tokens::PushTokens,
+};
+use rand::Rng;
+use rate_limiter::{RateLimiter, VerificationCodeLimiter};
+use routes::{devices, matches, user_available};
+use verification::{sms_sender::FileSmsSender, verification_codes::VerificationCodes};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // If we can't create database interface here, this is unrecoverable !
-    let database_interface = DataBaseInterface::new().await.unwrap();
-    let user_cleaner = AvailableUserCleaner::new(database_interface.clone());
-    user_cleaner.start();
+    let configuration = Configuration::load()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err.message))?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("migrate") => {
+            let dry_run = args.iter().any(|arg| arg == "--dry-run");
+            return migrate(&configuration, dry_run).await;
+        }
+        Some(command) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                std::format!("Unknown command {}, try \"migrate [--dry-run]\"", command),
+            ));
+        }
+    }
+    // Writes are published, so clients can follow their matches :
+    let bus = EventBus::new();
+    match configuration.database.storage {
+        // Nothing is persisted with the memory storage, use it for demos.
+        StorageBackend::Memory => {
+            let database_interface = PublishingStore::new(InMemoryStore::new(), bus.clone());
+            let notifications = (PushTokens::new(), NotifiedPairs::new());
+            run_server(database_interface, bus, notifications, configuration).await
+        }
+        StorageBackend::Mongo => {
+            // If we can't create database interface here, this is unrecoverable !
+            let database_interface = DataBaseInterface::new(&configuration.database)
+                .await
+                .map_err(|err| {
+                    std::io::Error::other(std::format!("Can't use the database : {}", err))
+                })?;
+            let pending = migrations::pending(&database_interface.database())
+                .await
+                .map_err(|err| {
+                    std::io::Error::other(std::format!("Can't list migrations : {}", err))
+                })?;
+            if !pending.is_empty() {
+                println!(
+                    "{} migrations are not applied, run \"nearby-back migrate\" !",
+                    pending.len()
+                );
+            }
+            // Shared by the instances, so each match is notified once :
+            let database = database_interface.database();
+            let notifications = (
+                PushTokens::in_database(&database),
+                NotifiedPairs::in_database(&database),
+            );
+            if configuration.database.change_streams {
+                // Writes of all the instances are seen by the change stream :
+                ChangeStreamWatcher::new(database_interface.available_collection(), bus.clone())
+                    .start();
+                run_server(database_interface, bus, notifications, configuration).await
+            } else {
+                let database_interface = PublishingStore::new(database_interface, bus.clone());
+                run_server(database_interface, bus, notifications, configuration).await
+            }
+        }
+    }
+}
+
+async fn run_server<S: AvailabilityStore>(
+    database_interface: S,
+    bus: EventBus,
+    (push_tokens, notified_pairs): (PushTokens, NotifiedPairs),
+    configuration: Configuration,
+) -> std::io::Result<()> {
+    let match_broker = MatchBroker::new(
+        bus.clone(),
+        configuration.search.default_radius_m,
+        configuration.privacy.clone(),
+    )
+    .start();
+
+    // There is no TTL index in memory :
+    if configuration.cleaner.enabled || configuration.database.storage == StorageBackend::Memory {
+        let user_cleaner = AvailableUserCleaner::new(
+            database_interface.clone(),
+            Duration::from_secs(configuration.cleaner.interval_s),
+        );
+        user_cleaner.start();
+    }
+
+    NotificationDispatcher::new(
+        database_interface.clone(),
+        LogPushSender::new(),
+        push_tokens.clone(),
+        notified_pairs,
+        configuration.search.default_radius_m,
+        bus,
+    )
+    .start();
 
+    let signer = create_token_signer(&configuration.authentication);
+    let hasher = create_phone_hasher(&configuration.hashing)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let verification_codes = VerificationCodes::new(&configuration.verification);
+    let sms_log_path = &configuration.verification.sms_log_path;
+    let sms_sender = FileSmsSender::new(if sms_log_path.is_empty() {
+        None
+    } else {
+        Some(sms_log_path.into())
+    });
+    let search_configuration = configuration.search.clone();
+    let validation_configuration = configuration.validation.clone();
+    let privacy_configuration = configuration.privacy.clone();
+    // Shared by all workers :
+    let rate_limiter = RateLimiter::new(&configuration.rate_limit);
+    let verification_code_limiter = VerificationCodeLimiter::new(&configuration.rate_limit);
     HttpServer::new(move || {
         App::new()
+            .wrap(Authentication::new(signer.clone()))
             .data(database_interface.clone())
-            .route(
-                "/user_available",
-                web::post().to(user_available::user_available),
-            )
-            .route(
-                "/contacts_availables_nearby",
-                web::get().to(user_available::get_nearby_friends),
-            )
+            .data(signer.clone())
+            .data(verification_codes.clone())
+            .data(sms_sender.clone())
+            .data(search_configuration.clone())
+            .data(validation_configuration.clone())
+            .data(privacy_configuration.clone())
+            .data(hasher.clone())
+            .data(rate_limiter.clone())
+            .data(verification_code_limiter.clone())
+            .data(push_tokens.clone())
+            .data(match_broker.clone())
+            .configure(routes::configure_payload_errors)
+            .configure(devices::configure::<FileSmsSender>)
+            .configure(user_available::configure::<S>)
+            .configure(matches::configure::<S>)
+            .default_service(web::route().to(routes::not_found))
     })
-    .bind("127.0.0.1:8080")?
+    .bind(&configuration.server.bind_address)?
     .run()
     .await
 }
+
+/**
+ * Apply the migrations of the available collection, or only tell what they
+ * would do with `dry_run`.
+ */
+async fn migrate(configuration: &Configuration, dry_run: bool) -> std::io::Result<()> {
+    if configuration.database.storage == StorageBackend::Memory {
+        println!("Nothing to migrate with the memory storage");
+        return Ok(());
+    }
+    let database = migrations::connect(&configuration.database)
+        .await
+        .map_err(|err| std::io::Error::other(std::format!("Can't use the database : {}", err)))?;
+    let collection_name = &configuration.database.collection_name;
+    let reports = migrations::run(&database, &database.collection(collection_name), dry_run)
+        .await
+        .map_err(|err| std::io::Error::other(std::format!("Migration failed : {}", err)))?;
+    // Documents are fixed, the indexes can be created :
+    if !dry_run {
+        schema::ensure_schema(&database, collection_name)
+            .await
+            .map_err(|err| std::io::Error::other(std::format!("{}", err)))?;
+    }
+    if reports.is_empty() {
+        println!("All migrations are applied");
+    }
+    for report in reports.iter() {
+        println!(
+            "Migration {} ({}) : {} documents {}",
+            report.version,
+            report.description,
+            report.documents,
+            if dry_run { "would change" } else { "changed" }
+        );
+    }
+    return Ok(());
+}
+
+fn create_token_signer(configuration: &AuthenticationConfiguration) -> TokenSigner {
+    let validity = chrono::Duration::hours(configuration.token_validity_hours);
+    if configuration.secret.is_empty() {
+        println!("No authentication secret configured, tokens won't survive a restart !");
+        let secret: [u8; 32] = rand::thread_rng().gen();
+        return TokenSigner::new(&secret, validity);
+    }
+    return TokenSigner::new(configuration.secret.as_bytes(), validity);
+}
+
+fn create_phone_hasher(configuration: &HashingConfiguration) -> Result<PhoneHasher, String> {
+    if configuration.keys.is_empty() {
+        println!("No hashing key configured, matches won't survive a restart !");
+        return Ok(PhoneHasher::with_random_key());
+    }
+    return PhoneHasher::new(configuration);
+}
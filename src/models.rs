@@ -1 +1,4 @@
-pub mod user;
\ No newline at end of file
+pub mod device;
+pub mod nearby_query;
+pub mod nearby_request;
+pub mod user;
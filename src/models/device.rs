@@ -0,0 +1,47 @@
+use crate::notification::payloads::Platform;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/**
+ * Ask for a verification code to be sent by SMS to this phone number.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct VerificationCodeRequest {
+    pub phone_number: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct VerificationCodeSent {
+    pub expires_at: DateTime<Utc>,
+}
+
+/**
+ * A device proving it owns a phone number with the code it received, and
+ * asking for a token to act on behalf of this phone number.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct DeviceRegistration {
+    pub phone_number: String,
+    pub verification_code: String,
+    pub device_id: String,
+}
+
+/**
+ * Token to send in the `Authorization: Bearer` header of the following requests,
+ * bound to `phone_number_hash`.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct DeviceToken {
+    pub phone_number_hash: String,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/**
+ * Where to send push notifications for the authenticated phone number hash.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct PushTokenRegistration {
+    pub platform: Platform,
+    pub token: String,
+}
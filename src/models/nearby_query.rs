@@ -0,0 +1,57 @@
+use crate::error::FieldError;
+use crate::models::nearby_request::NearbyRequest;
+use serde::{Deserialize, Serialize};
+
+/**
+ * Parameters of a nearby contacts search. It is read from the query string on
+ * GET, and from the JSON body on POST.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct NearbyQuery {
+    pub phone_number_hash: String,
+    #[serde(alias = "lat")]
+    pub latitude: f64,
+    #[serde(alias = "lon")]
+    pub longitude: f64,
+    #[serde(default, alias = "radius", skip_serializing_if = "Option::is_none")]
+    pub max_distance_m: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    /// Only return contacts that are also in our own contacts.
+    #[serde(default)]
+    pub mutual: bool,
+}
+
+impl From<NearbyRequest> for NearbyQuery {
+    fn from(request: NearbyRequest) -> Self {
+        NearbyQuery {
+            phone_number_hash: request.user.phone_number_hash,
+            latitude: request.user.latitude,
+            longitude: request.user.longitude,
+            max_distance_m: request.max_distance_m,
+            limit: request.limit,
+            mutual: false,
+        }
+    }
+}
+
+impl NearbyQuery {
+    /**
+     * Check the location searched from, and return all the fields that are
+     * wrong. NaN compares false with everything, so it is refused explicitly.
+     */
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors: Vec<FieldError> = Vec::new();
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            errors.push(FieldError::new("latitude", "must be between -90 and 90"));
+        }
+        if !(-180.0..=180.0).contains(&self.longitude) {
+            errors.push(FieldError::new("longitude", "must be between -180 and 180"));
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        return Err(errors);
+    }
+}
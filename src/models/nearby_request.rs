@@ -0,0 +1,17 @@
+use crate::models::user::User;
+use serde::{Deserialize, Serialize};
+
+/**
+ * Legacy body of a nearby contacts request : the full user asking, and optional
+ * search parameters. New clients should send a `NearbyQuery` instead, this one
+ * is only accepted on the compatibility path.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct NearbyRequest {
+    #[serde(flatten)]
+    pub user: User,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_distance_m: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
@@ -1,89 +1,262 @@
-use chrono::{DateTime, FixedOffset, Utc};
-use mongodb::bson::{bson, doc, Bson, Document};
+use crate::configuration::ValidationConfiguration;
+use crate::error::FieldError;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Deserialize, Serialize)]
+/**
+ * How precisely a user accepts to be located by its contacts.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocationPrecision {
+    /// Contacts get the exact distance.
+    Exact,
+    /// The default : the location is snapped to a grid, and contacts only get a distance bucket.
+    #[default]
+    Approximate,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct User {
     pub phone_number_hash: String,
     pub latitude: f64,
     pub longitude: f64,
     pub available_until: DateTime<FixedOffset>,
     pub contacts_phone_number_hash: Vec<String>,
+    #[serde(default)]
+    pub location_precision: LocationPrecision,
+    /// Shown to contacts, like "At the beach".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_message: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct LocalizedUser {
     pub phone_number_hash: String,
-    pub distance: f32,
+    /// Only given for users who accept to be located exactly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f32>,
+    /// Like "<2 km", always given to clients.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance_bucket: Option<String>,
+    #[serde(default)]
+    pub location_precision: LocationPrecision,
+    /// Not given with unavailable match events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_until: Option<DateTime<FixedOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_message: Option<String>,
+    /// Degrees clockwise from north, from the searcher to the contact. Only
+    /// given with the exact distance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bearing: Option<f32>,
+    /// Like "north-east", always given to clients when the bearing is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction: Option<String>,
+    /// When the contact last posted its location, not given with match events
+    /// which are sent right away.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<FixedOffset>>,
+}
+
+/**
+ * Answer to a user becoming available.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct UserAvailable {
+    /// Nearby searches return keyed hashes : this gives the contact hash sent
+    /// by the client for each of them.
+    pub keyed_contacts: HashMap<String, String>,
+}
+
+/**
+ * Answer to a request to stop being available.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct UserRemoved {
+    /// False if the user was already not available.
+    pub removed: bool,
 }
 
 impl User {
-    pub fn to_bson_document(&self) -> Document {
-        let utc_available_datetime: DateTime<Utc> = DateTime::from(self.available_until);
-        let mut contacts_phone =
-            mongodb::bson::Array::with_capacity(self.contacts_phone_number_hash.len());
+    /**
+     * Check that this user can be stored, and return all the fields that are
+     * wrong if it can't.
+     */
+    pub fn validate(
+        &self,
+        rules: &ValidationConfiguration,
+        now: DateTime<Utc>,
+    ) -> Result<(), Vec<FieldError>> {
+        let mut errors: Vec<FieldError> = Vec::new();
+        if let Some(message) = check_hash(&self.phone_number_hash, rules) {
+            errors.push(FieldError::new("phone_number_hash", &message));
+        }
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            errors.push(FieldError::new("latitude", "must be between -90 and 90"));
+        }
+        if !(-180.0..=180.0).contains(&self.longitude) {
+            errors.push(FieldError::new("longitude", "must be between -180 and 180"));
+        }
+        if self.available_until <= now {
+            errors.push(FieldError::new("available_until", "must be in the future"));
+        } else if self.available_until > now + Duration::hours(rules.max_availability_hours) {
+            errors.push(FieldError::new(
+                "available_until",
+                &std::format!(
+                    "must be less than {} hours from now",
+                    rules.max_availability_hours
+                ),
+            ));
+        }
+        if self.contacts_phone_number_hash.len() > rules.max_contacts {
+            errors.push(FieldError::new(
+                "contacts_phone_number_hash",
+                &std::format!("must not have more than {} contacts", rules.max_contacts),
+            ));
+        }
+        for (index, contact) in self.contacts_phone_number_hash.iter().enumerate() {
+            if let Some(message) = check_hash(contact, rules) {
+                errors.push(FieldError::new(
+                    &std::format!("contacts_phone_number_hash[{}]", index),
+                    &message,
+                ));
+            }
+        }
+        if let Some(status_message) = &self.status_message {
+            if status_message.chars().count() > rules.max_status_message_length {
+                errors.push(FieldError::new(
+                    "status_message",
+                    &std::format!(
+                        "must not be longer than {} characters",
+                        rules.max_status_message_length
+                    ),
+                ));
+            } else if status_message.chars().any(char::is_control) {
+                errors.push(FieldError::new(
+                    "status_message",
+                    "must not contain control characters",
+                ));
+            }
+        }
 
-        for contact_phone_hash in self.contacts_phone_number_hash.iter() {
-            contacts_phone.push(Bson::from(contact_phone_hash));
+        if errors.is_empty() {
+            return Ok(());
         }
+        return Err(errors);
+    }
+}
 
-        let res = doc! {
-            "phone_number_hash": self.phone_number_hash.clone(),
-            "location": doc! {
-                "type": "Point",
-                "coordinates": bson!([self.longitude, self.latitude])
-            },
-            "available_until": utc_available_datetime,
-            "contacts_phone_number_hash": contacts_phone
-        };
-        return res;
+/**
+ * Hashes are sent as hexadecimal or base64 strings, we refuse anything else.
+ */
+fn check_hash(hash: &str, rules: &ValidationConfiguration) -> Option<String> {
+    if hash.is_empty() {
+        return Some(String::from("must not be empty"));
+    }
+    if hash.len() > rules.max_hash_length {
+        return Some(std::format!(
+            "must not be longer than {} characters",
+            rules.max_hash_length
+        ));
+    }
+    let is_hash_character = |c: char| {
+        c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=' || c == '-' || c == '_'
+    };
+    if !hash.chars().all(is_hash_character) {
+        return Some(String::from("must be an hexadecimal or base64 string"));
     }
+    return None;
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    #[test]
-    pub fn user_are_serializable_in_bson() {
-        let user = User {
-            phone_number_hash: String::from("01234"),
+
+    fn valid_user(now: DateTime<Utc>) -> User {
+        User {
+            phone_number_hash: String::from("9f86d081884c7d65"),
             latitude: 43.5,
             longitude: 5.8952895,
-            available_until: DateTime::parse_from_rfc3339("2021-01-01T12:21:33+01:00")
-                .expect("Can't parse date"),
-            contacts_phone_number_hash: vec![String::from("56789"), String::from("0000000")],
-        };
+            available_until: DateTime::from(now + Duration::hours(2)),
+            location_precision: LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![String::from("YWJjZGVm+/==")],
+        }
+    }
+
+    #[test]
+    pub fn valid_user_passes_validation() {
+        let now = Utc::now();
+        let rules = ValidationConfiguration::default();
+        assert_eq!(valid_user(now).validate(&rules, now), Ok(()));
+    }
 
-        let bson_user = user.to_bson_document();
+    #[test]
+    pub fn invalid_fields_are_all_reported() {
+        let now = Utc::now();
+        let rules = ValidationConfiguration::default();
+        let mut user = valid_user(now);
+        user.phone_number_hash = String::new();
+        user.latitude = 91.0;
+        user.longitude = f64::NAN;
+        user.available_until = DateTime::from(now - Duration::minutes(1));
+        user.contacts_phone_number_hash = vec![String::from("ok"), String::from("not a hash")];
+        user.status_message = Some(String::from("Line\nbreak"));
+
+        let fields: Vec<String> = user
+            .validate(&rules, now)
+            .expect_err("User should be invalid")
+            .into_iter()
+            .map(|error| error.field)
+            .collect();
         assert_eq!(
-            bson_user
-                .get_str("phone_number_hash")
-                .expect("can't find phone number hash in bson doc."),
-            user.phone_number_hash
+            fields,
+            vec![
+                "phone_number_hash",
+                "latitude",
+                "longitude",
+                "available_until",
+                "contacts_phone_number_hash[1]",
+                "status_message"
+            ]
         );
+    }
 
-        let location = bson_user
-            .get_document("location")
-            .expect("can't find latitude in BSON Document");
+    #[test]
+    pub fn availability_window_and_contacts_are_bounded() {
+        let now = Utc::now();
+        let rules = ValidationConfiguration {
+            max_availability_hours: 12,
+            max_contacts: 2,
+            max_hash_length: 8,
+            max_status_message_length: 10,
+        };
+        let mut user = valid_user(now);
+        user.available_until = DateTime::from(now + Duration::hours(13));
+        user.contacts_phone_number_hash = vec![
+            String::from("a"),
+            String::from("b"),
+            String::from("0123456789"),
+        ];
+        user.status_message = Some(String::from("Free for a drink"));
+
+        let fields: Vec<String> = user
+            .validate(&rules, now)
+            .expect_err("User should be invalid")
+            .into_iter()
+            .map(|error| error.field)
+            .collect();
         assert_eq!(
-            location.get_str("type").expect("Can't find geoJSON Type"),
-            "Point"
+            fields,
+            vec![
+                "phone_number_hash",
+                "available_until",
+                "contacts_phone_number_hash",
+                "contacts_phone_number_hash[2]",
+                "status_message"
+            ]
         );
-        let coordinates = location
-            .get_array("coordinates")
-            .expect("Can't find coordinates");
-        let longitude = coordinates
-            .get(0)
-            .expect("Coordinates array doesn't have the good size")
-            .as_f64()
-            .expect("Longitude is not f64 !!");
-
-        let latitude = coordinates
-            .get(1)
-            .expect("Coordinates array doesn't have the good size")
-            .as_f64()
-            .expect("Longitude is not f64 !!");
-        assert!((longitude - user.longitude).abs() < 0.0001);
-        assert!((latitude - user.latitude).abs() < 0.0001);
     }
 }
@@ -0,0 +1,5 @@
+pub mod notification_dispatcher;
+pub mod notified_pairs;
+pub mod payloads;
+pub mod push_sender;
+pub mod push_tokens;
@@ -0,0 +1,255 @@
+use crate::database::availability_store::AvailabilityStore;
+use crate::error::NearbyError;
+use crate::events::event_bus::{AvailabilityEvent, EventBus};
+use crate::models::user;
+use crate::notification::{
+    notified_pairs::NotifiedPairs, payloads::PushNotification, push_sender::PushSender,
+    push_tokens::PushTokens,
+};
+use actix::prelude::*;
+use chrono::Utc;
+use futures::channel::mpsc;
+
+/**
+ * Notify both users when a mutual match appears : they are available, close,
+ * and picked each other. Each pair is only notified once while the user who
+ * triggered the match is available.
+ * It follows the events of the bus, so routes don't wait for push providers,
+ * and with change streams, users matching on another instance are notified too.
+ * All the instances then see each match : with MongoDB, push tokens and notified
+ * pairs are shared, so only one of them sends the notifications.
+ */
+pub struct NotificationDispatcher<S: AvailabilityStore, P: PushSender> {
+    database_interface: S,
+    sender: P,
+    push_tokens: PushTokens,
+    radius_m: f32,
+    /// Subscribed when created, not to miss the events sent before the start.
+    events: Option<mpsc::UnboundedReceiver<AvailabilityEvent>>,
+    notified: NotifiedPairs,
+}
+
+impl<S: AvailabilityStore, P: PushSender> Actor for NotificationDispatcher<S, P> {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        println!("Starting Notification Dispatcher");
+        if let Some(events) = self.events.take() {
+            ctx.add_stream(events);
+        }
+    }
+}
+
+impl<S: AvailabilityStore, P: PushSender> StreamHandler<AvailabilityEvent>
+    for NotificationDispatcher<S, P>
+{
+    fn handle(&mut self, event: AvailabilityEvent, ctx: &mut Context<Self>) {
+        let user = match event {
+            AvailabilityEvent::Available(user) => user,
+            AvailabilityEvent::Unavailable { .. } => return,
+        };
+        let dispatch = NotificationDispatcher::notify_matches(
+            self.database_interface.clone(),
+            self.sender.clone(),
+            self.push_tokens.clone(),
+            self.radius_m,
+            self.notified.clone(),
+            user,
+        );
+        ctx.spawn(
+            async move {
+                if let Err(err) = dispatch.await {
+                    println!("Error when notifying matches : {}", err);
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+impl<S: AvailabilityStore, P: PushSender> NotificationDispatcher<S, P> {
+    pub fn new(
+        database_interface: S,
+        sender: P,
+        push_tokens: PushTokens,
+        notified: NotifiedPairs,
+        radius_m: f32,
+        bus: EventBus,
+    ) -> Self {
+        NotificationDispatcher {
+            database_interface,
+            sender,
+            push_tokens,
+            radius_m,
+            events: Some(bus.subscribe()),
+            notified,
+        }
+    }
+
+    /**
+     * Return the number of notifications sent.
+     */
+    pub async fn notify_matches(
+        database_interface: S,
+        sender: P,
+        push_tokens: PushTokens,
+        radius_m: f32,
+        notified: NotifiedPairs,
+        user: user::User,
+    ) -> Result<usize, NearbyError> {
+        let matches = database_interface
+            .get_contacts_available_nearby(
+                &user.phone_number_hash,
+                user.latitude,
+                user.longitude,
+                radius_m,
+                None,
+                true,
+            )
+            .await?;
+        let mut new_matches: Vec<String> = Vec::new();
+        for contact in matches.into_iter() {
+            if notified
+                .claim(
+                    &user.phone_number_hash,
+                    &contact.phone_number_hash,
+                    user.available_until,
+                    Utc::now(),
+                )
+                .await?
+            {
+                new_matches.push(contact.phone_number_hash);
+            }
+        }
+
+        let mut sent = 0;
+        for contact in new_matches.iter() {
+            println!(
+                "User Phone : {:0} matches with {:1}",
+                user.phone_number_hash, contact
+            );
+            for (recipient, about) in [
+                (contact, &user.phone_number_hash),
+                (&user.phone_number_hash, contact),
+            ] {
+                let token = match push_tokens.get(recipient).await? {
+                    Some(token) => token,
+                    // This device never gave us a push token :
+                    None => continue,
+                };
+                sender
+                    .send(&PushNotification {
+                        platform: token.platform,
+                        device_token: token.token,
+                        title: String::from("Nearby"),
+                        body: String::from("A friend is available nearby !"),
+                        phone_number_hash: about.clone(),
+                    })
+                    .await?;
+                sent += 1;
+            }
+        }
+        return Ok(sent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::in_memory_store::InMemoryStore;
+    use crate::events::publishing_store::PublishingStore;
+    use crate::notification::{
+        payloads::Platform, push_sender::MockPushSender, push_tokens::PushToken,
+    };
+    use chrono::{DateTime, Duration};
+
+    fn available(phone_number_hash: &str, contacts: &[&str]) -> user::User {
+        user::User {
+            phone_number_hash: String::from(phone_number_hash),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: DateTime::from(Utc::now() + Duration::hours(1)),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: contacts.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /**
+     * Events go through the bus, give them some time.
+     */
+    async fn wait_for_notifications(
+        sender: &MockPushSender,
+        count: usize,
+    ) -> Vec<PushNotification> {
+        for _ in 0..20 {
+            if sender.sent().len() >= count {
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        return sender.sent();
+    }
+
+    #[actix_rt::test]
+    async fn test_mutual_matches_are_notified_once() {
+        let bus = EventBus::new();
+        let database = PublishingStore::new(InMemoryStore::new(), bus.clone());
+        let sender = MockPushSender::new();
+        let push_tokens = PushTokens::new();
+        for (phone_number_hash, platform) in [("Peppa", Platform::Fcm), ("Rebecca", Platform::Apns)]
+        {
+            push_tokens
+                .register(
+                    &[String::from(phone_number_hash)],
+                    PushToken {
+                        platform,
+                        token: std::format!("{}-token", phone_number_hash),
+                    },
+                )
+                .await
+                .expect("Can't register token");
+        }
+        NotificationDispatcher::new(
+            database.clone(),
+            sender.clone(),
+            push_tokens,
+            NotifiedPairs::new(),
+            10_000.0,
+            bus,
+        )
+        .start();
+
+        // Suzy picked Peppa, but Peppa didn't pick Suzy :
+        for user in [
+            available("Rebecca", &["Peppa"]),
+            available("Suzy", &["Peppa"]),
+            available("Peppa", &["Rebecca"]),
+        ] {
+            database
+                .set_user_available(&user)
+                .await
+                .expect("Can't add user");
+        }
+
+        let sent = wait_for_notifications(&sender, 2).await;
+        assert_eq!(sent.len(), 2);
+        let to_rebecca = sent
+            .iter()
+            .find(|notification| notification.device_token == "Rebecca-token")
+            .expect("Rebecca must be notified");
+        assert_eq!(to_rebecca.platform, Platform::Apns);
+        assert_eq!(to_rebecca.phone_number_hash, "Peppa");
+        let to_peppa = sent
+            .iter()
+            .find(|notification| notification.device_token == "Peppa-token")
+            .expect("Peppa must be notified");
+        assert_eq!(to_peppa.phone_number_hash, "Rebecca");
+
+        // Peppa moves a bit, it is not a new match :
+        database
+            .set_user_available(&available("Peppa", &["Rebecca"]))
+            .await
+            .expect("Can't add user");
+        assert_eq!(wait_for_notifications(&sender, 3).await.len(), 2);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::error::NearbyError;
+use chrono::{DateTime, FixedOffset, Utc};
+use mongodb::{bson::doc, options::UpdateOptions, Collection, Database};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// One document per pair, removed by a TTL index on `until`.
+pub const NOTIFIED_PAIRS_COLLECTION: &str = "notified_pairs";
+
+#[derive(Clone)]
+enum Backend {
+    Memory(Arc<Mutex<HashMap<String, DateTime<FixedOffset>>>>),
+    Database(Collection),
+}
+
+/**
+ * Pairs of users already notified, until when. With change streams, all the
+ * instances see the same match : the first one to claim the pair in MongoDB
+ * notifies it, the others skip it.
+ */
+#[derive(Clone)]
+pub struct NotifiedPairs {
+    backend: Backend,
+}
+
+impl Default for NotifiedPairs {
+    fn default() -> Self {
+        NotifiedPairs {
+            backend: Backend::Memory(Arc::new(Mutex::new(HashMap::new()))),
+        }
+    }
+}
+
+impl NotifiedPairs {
+    pub fn new() -> Self {
+        NotifiedPairs::default()
+    }
+
+    pub fn in_database(database: &Database) -> Self {
+        NotifiedPairs {
+            backend: Backend::Database(database.collection(NOTIFIED_PAIRS_COLLECTION)),
+        }
+    }
+
+    /**
+     * Return true if this pair must be notified now, and remember it until
+     * `until`. The order of the users doesn't matter.
+     */
+    pub async fn claim(
+        &self,
+        user: &str,
+        contact: &str,
+        until: DateTime<FixedOffset>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, NearbyError> {
+        // Hashes never contain spaces :
+        let pair = if contact < user {
+            std::format!("{} {}", contact, user)
+        } else {
+            std::format!("{} {}", user, contact)
+        };
+        match &self.backend {
+            Backend::Memory(pairs) => {
+                let mut pairs = pairs
+                    .lock()
+                    .map_err(|_| NearbyError::Internal(String::from("Dispatcher is poisoned")))?;
+                let now: DateTime<FixedOffset> = DateTime::from(now);
+                pairs.retain(|_, until| *until > now);
+                return Ok(pairs.insert(pair, until).is_none());
+            }
+            Backend::Database(collection) => {
+                // Only matches expired pairs, or none : then the upsert fails
+                // on the _id of a pair that is still notified.
+                let options = UpdateOptions::builder().upsert(true).build();
+                let res = collection
+                    .update_one(
+                        doc! {"_id": pair, "until": {"$lte": now}},
+                        doc! {"$set": {"until": DateTime::<Utc>::from(until)}},
+                        options,
+                    )
+                    .await;
+                return match res.map_err(NearbyError::from) {
+                    Ok(_) => Ok(true),
+                    Err(NearbyError::Conflict(_)) => Ok(false),
+                    Err(err) => Err(err),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn test_pairs_are_claimed_once_until_they_expire() {
+        let pairs = NotifiedPairs::new();
+        let now = Utc::now();
+        let until = DateTime::from(now + Duration::hours(1));
+        assert!(pairs
+            .claim("Peppa", "Rebecca", until, now)
+            .await
+            .expect("Can't claim"));
+        assert!(!pairs
+            .claim("Rebecca", "Peppa", until, now)
+            .await
+            .expect("Can't claim"));
+        assert!(pairs
+            .claim("Rebecca", "Peppa", until, now + Duration::hours(2))
+            .await
+            .expect("Can't claim"));
+    }
+}
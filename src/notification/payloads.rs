@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    /// Firebase Cloud Messaging, for Android.
+    Fcm,
+    /// Apple Push Notification service, for iOS.
+    Apns,
+}
+
+/**
+ * A notification for one device, whatever its platform.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct PushNotification {
+    pub platform: Platform,
+    pub device_token: String,
+    pub title: String,
+    pub body: String,
+    /// Keyed hash of the contact this notification is about.
+    pub phone_number_hash: String,
+}
+
+/**
+ * Body of the FCM HTTP v1 API `messages:send` method.
+ */
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FcmRequest {
+    pub message: FcmMessage,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FcmMessage {
+    pub token: String,
+    pub notification: FcmNotification,
+    /// FCM only accepts strings here.
+    pub data: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FcmNotification {
+    pub title: String,
+    pub body: String,
+}
+
+/**
+ * Body of an APNs request, the device token goes in the URL
+ * (`/3/device/<token>`), not in the payload.
+ */
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApnsPayload {
+    pub aps: Aps,
+    pub phone_number_hash: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Aps {
+    pub alert: ApsAlert,
+    pub sound: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApsAlert {
+    pub title: String,
+    pub body: String,
+}
+
+impl PushNotification {
+    pub fn to_fcm(&self) -> FcmRequest {
+        let mut data = HashMap::new();
+        data.insert(
+            String::from("phone_number_hash"),
+            self.phone_number_hash.clone(),
+        );
+        FcmRequest {
+            message: FcmMessage {
+                token: self.device_token.clone(),
+                notification: FcmNotification {
+                    title: self.title.clone(),
+                    body: self.body.clone(),
+                },
+                data,
+            },
+        }
+    }
+
+    pub fn to_apns(&self) -> ApnsPayload {
+        ApnsPayload {
+            aps: Aps {
+                alert: ApsAlert {
+                    title: self.title.clone(),
+                    body: self.body.clone(),
+                },
+                sound: String::from("default"),
+            },
+            phone_number_hash: self.phone_number_hash.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn notification(platform: Platform) -> PushNotification {
+        PushNotification {
+            platform,
+            device_token: String::from("device-token"),
+            title: String::from("Nearby"),
+            body: String::from("A friend is available nearby"),
+            phone_number_hash: String::from("v1:abcd"),
+        }
+    }
+
+    #[test]
+    fn test_fcm_payload() {
+        let payload = serde_json::to_value(notification(Platform::Fcm).to_fcm())
+            .expect("Can't serialize payload");
+        assert_eq!(
+            payload,
+            json!({"message": {
+                "token": "device-token",
+                "notification": {"title": "Nearby", "body": "A friend is available nearby"},
+                "data": {"phone_number_hash": "v1:abcd"}
+            }})
+        );
+    }
+
+    #[test]
+    fn test_apns_payload() {
+        let payload = serde_json::to_value(notification(Platform::Apns).to_apns())
+            .expect("Can't serialize payload");
+        assert_eq!(
+            payload,
+            json!({
+                "aps": {
+                    "alert": {"title": "Nearby", "body": "A friend is available nearby"},
+                    "sound": "default"
+                },
+                "phone_number_hash": "v1:abcd"
+            })
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use crate::error::NearbyError;
+use crate::notification::payloads::{Platform, PushNotification};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/**
+ * Something able to deliver a notification to a device.
+ * Real providers (FCM, APNs) are remote services, that's why sending is asynchronous.
+ */
+pub trait PushSender: Clone + Send + Unpin + 'static {
+    async fn send(&self, notification: &PushNotification) -> Result<(), NearbyError>;
+}
+
+/**
+ * Doesn't send anything : prints the payload the provider would receive.
+ * Use it for local development, until we have FCM and APNs credentials.
+ */
+#[derive(Clone, Default)]
+pub struct LogPushSender {}
+
+impl LogPushSender {
+    pub fn new() -> Self {
+        LogPushSender::default()
+    }
+}
+
+impl PushSender for LogPushSender {
+    async fn send(&self, notification: &PushNotification) -> Result<(), NearbyError> {
+        let payload = match notification.platform {
+            Platform::Fcm => serde_json::to_string(&notification.to_fcm()),
+            Platform::Apns => serde_json::to_string(&notification.to_apns()),
+        }
+        .map_err(|err| NearbyError::Internal(std::format!("Can't serialize push : {}", err)))?;
+        println!(
+            "Push to {:?} device {} : {}",
+            notification.platform, notification.device_token, payload
+        );
+        return Ok(());
+    }
+}
+
+/**
+ * Keeps the notifications, so tests can check what would have been sent.
+ */
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MockPushSender {
+    sent: Arc<Mutex<Vec<PushNotification>>>,
+}
+
+#[cfg(test)]
+impl MockPushSender {
+    pub fn new() -> Self {
+        MockPushSender::default()
+    }
+
+    pub fn sent(&self) -> Vec<PushNotification> {
+        return self.sent.lock().expect("Mock is poisoned").clone();
+    }
+}
+
+#[cfg(test)]
+impl PushSender for MockPushSender {
+    async fn send(&self, notification: &PushNotification) -> Result<(), NearbyError> {
+        self.sent
+            .lock()
+            .map_err(|_| NearbyError::Internal(String::from("Mock is poisoned")))?
+            .push(notification.clone());
+        return Ok(());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::database::documents;
+use crate::error::NearbyError;
+use crate::notification::payloads::Platform;
+use mongodb::{bson::doc, options::ReplaceOptions, Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// One document per keyed hash, with the hash as `_id`.
+const PUSH_TOKENS_COLLECTION: &str = "push_tokens";
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PushToken {
+    pub platform: Platform,
+    pub token: String,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Memory(Arc<RwLock<HashMap<String, PushToken>>>),
+    Database(Collection),
+}
+
+/**
+ * Push token of the last device registered for each phone number hash.
+ * With MongoDB, tokens are shared by all the instances : a device registers on
+ * one of them, and its matches can be found by any of them. In memory, devices
+ * register again when the server restarts (the app sends its token each time
+ * it starts).
+ */
+#[derive(Clone)]
+pub struct PushTokens {
+    backend: Backend,
+}
+
+impl Default for PushTokens {
+    fn default() -> Self {
+        PushTokens {
+            backend: Backend::Memory(Arc::new(RwLock::new(HashMap::new()))),
+        }
+    }
+}
+
+impl PushTokens {
+    pub fn new() -> Self {
+        PushTokens::default()
+    }
+
+    pub fn in_database(database: &Database) -> Self {
+        PushTokens {
+            backend: Backend::Database(database.collection(PUSH_TOKENS_COLLECTION)),
+        }
+    }
+
+    /**
+     * Stored users have keyed hashes, so tokens are registered for all the
+     * keyed hashes of the user.
+     */
+    pub async fn register(
+        &self,
+        keyed_hashes: &[String],
+        token: PushToken,
+    ) -> Result<(), NearbyError> {
+        match &self.backend {
+            Backend::Memory(tokens) => {
+                let mut tokens = tokens.write().map_err(|_| Self::lock_error())?;
+                for keyed_hash in keyed_hashes {
+                    tokens.insert(keyed_hash.clone(), token.clone());
+                }
+            }
+            Backend::Database(collection) => {
+                let options = ReplaceOptions::builder().upsert(true).build();
+                for keyed_hash in keyed_hashes {
+                    let mut document = documents::to_document(&token)?;
+                    document.insert("_id", keyed_hash.clone());
+                    collection
+                        .replace_one(doc! {"_id": keyed_hash}, document, options.clone())
+                        .await?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    pub async fn get(&self, keyed_hash: &str) -> Result<Option<PushToken>, NearbyError> {
+        return match &self.backend {
+            Backend::Memory(tokens) => {
+                let tokens = tokens.read().map_err(|_| Self::lock_error())?;
+                Ok(tokens.get(keyed_hash).cloned())
+            }
+            Backend::Database(collection) => {
+                match collection.find_one(doc! {"_id": keyed_hash}, None).await? {
+                    Some(document) => Ok(Some(documents::from_document(document)?)),
+                    None => Ok(None),
+                }
+            }
+        };
+    }
+
+    fn lock_error() -> NearbyError {
+        NearbyError::Internal(String::from("Push tokens are poisoned"))
+    }
+}
@@ -0,0 +1,168 @@
+use crate::configuration::PrivacyConfiguration;
+use crate::models::user::{LocalizedUser, LocationPrecision, User};
+
+/// Length of one degree of latitude (and of longitude at the equator).
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/**
+ * With exact distances, anyone can find where a friend is by searching from
+ * three places. So unless the user accepts it, we blur its location before
+ * storing it, and hide the distance behind a bucket.
+ */
+pub fn blur_user(user: &User, configuration: &PrivacyConfiguration) -> User {
+    let mut blurred = user.clone();
+    if user.location_precision == LocationPrecision::Approximate {
+        let (latitude, longitude) =
+            snap_to_grid(user.latitude, user.longitude, configuration.grid_size_m);
+        blurred.latitude = latitude;
+        blurred.longitude = longitude;
+    }
+    return blurred;
+}
+
+pub fn blur_result(localized: &mut LocalizedUser, configuration: &PrivacyConfiguration) {
+    localized.distance_bucket = localized
+        .distance
+        .map(|distance| distance_bucket(distance, &configuration.distance_buckets_m));
+    localized.direction = localized.bearing.map(compass_direction);
+    if localized.location_precision == LocationPrecision::Approximate {
+        localized.distance = None;
+        localized.bearing = None;
+    }
+}
+
+/**
+ * One of the eight main directions, like "north-east".
+ */
+pub fn compass_direction(bearing_deg: f32) -> String {
+    const DIRECTIONS: [&str; 8] = [
+        "north",
+        "north-east",
+        "east",
+        "south-east",
+        "south",
+        "south-west",
+        "west",
+        "north-west",
+    ];
+    let index = (bearing_deg.rem_euclid(360.0) / 45.0).round() as usize % DIRECTIONS.len();
+    return String::from(DIRECTIONS[index]);
+}
+
+/**
+ * Move a point to the center of its grid cell. Cells are `grid_size_m` high,
+ * and about as wide (longitude degrees get shorter near the poles).
+ */
+pub fn snap_to_grid(latitude: f64, longitude: f64, grid_size_m: f64) -> (f64, f64) {
+    if grid_size_m <= 0.0 {
+        return (latitude, longitude);
+    }
+    let latitude_step = grid_size_m / METERS_PER_DEGREE;
+    let snapped_latitude = ((latitude / latitude_step).round() * latitude_step).clamp(-90.0, 90.0);
+    // Don't divide by zero at the poles :
+    let longitude_step =
+        grid_size_m / (METERS_PER_DEGREE * snapped_latitude.to_radians().cos().max(0.01));
+    let mut snapped_longitude = (longitude / longitude_step).round() * longitude_step;
+    if snapped_longitude > 180.0 {
+        snapped_longitude -= 360.0;
+    } else if snapped_longitude < -180.0 {
+        snapped_longitude += 360.0;
+    }
+    return (snapped_latitude, snapped_longitude);
+}
+
+/**
+ * Label of the first bucket the distance fits in, like "<500 m" or "<2 km".
+ */
+pub fn distance_bucket(distance_m: f32, buckets_m: &[f32]) -> String {
+    return match buckets_m.iter().find(|bucket| distance_m < **bucket) {
+        Some(bucket) => std::format!("<{}", format_distance(*bucket)),
+        None => match buckets_m.last() {
+            Some(bucket) => std::format!(">{}", format_distance(*bucket)),
+            None => String::from("nearby"),
+        },
+    };
+}
+
+fn format_distance(distance_m: f32) -> String {
+    if distance_m >= 1_000.0 {
+        return std::format!("{} km", distance_m / 1_000.0);
+    }
+    return std::format!("{} m", distance_m);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::in_memory_store::haversine_distance_m;
+
+    #[test]
+    fn test_distances_are_bucketed() {
+        let buckets = PrivacyConfiguration::default().distance_buckets_m;
+        assert_eq!(distance_bucket(12.0, &buckets), "<500 m");
+        assert_eq!(distance_bucket(500.0, &buckets), "<1 km");
+        assert_eq!(distance_bucket(1_500.0, &buckets), "<2 km");
+        assert_eq!(distance_bucket(25_000.0, &buckets), ">20 km");
+        assert_eq!(distance_bucket(2_500.0, &[2_500.0, 3_500.0]), "<3.5 km");
+    }
+
+    #[test]
+    fn test_locations_are_snapped_to_the_grid() {
+        let (latitude, longitude) = snap_to_grid(43.123456, 6.123456, 500.0);
+        // We move, but not too much :
+        let moved = haversine_distance_m(43.123456, 6.123456, latitude, longitude);
+        assert!(moved > 1.0 && moved < 500.0);
+        // And close points end up at the same place :
+        assert_eq!(
+            snap_to_grid(43.123466, 6.123446, 500.0),
+            (latitude, longitude)
+        );
+
+        assert_eq!(
+            snap_to_grid(43.123456, 6.123456, 0.0),
+            (43.123456, 6.123456)
+        );
+        let (_, longitude) = snap_to_grid(0.0, 179.999, 100_000.0);
+        assert!((-180.0..=180.0).contains(&longitude));
+    }
+
+    #[test]
+    fn test_only_exact_users_give_their_distance() {
+        let configuration = PrivacyConfiguration::default();
+        let mut exact = LocalizedUser {
+            phone_number_hash: String::from("Rebecca"),
+            distance: Some(1_234.0),
+            distance_bucket: None,
+            location_precision: LocationPrecision::Exact,
+            available_until: None,
+            status_message: None,
+            bearing: Some(10.0),
+            direction: None,
+            updated_at: None,
+        };
+        blur_result(&mut exact, &configuration);
+        assert_eq!(exact.distance, Some(1_234.0));
+        assert_eq!(exact.distance_bucket.as_deref(), Some("<2 km"));
+        assert_eq!(exact.bearing, Some(10.0));
+        assert_eq!(exact.direction.as_deref(), Some("north"));
+
+        let mut approximate = LocalizedUser {
+            location_precision: LocationPrecision::Approximate,
+            ..exact
+        };
+        blur_result(&mut approximate, &configuration);
+        assert_eq!(approximate.distance, None);
+        assert_eq!(approximate.distance_bucket.as_deref(), Some("<2 km"));
+        assert_eq!(approximate.bearing, None);
+        assert_eq!(approximate.direction.as_deref(), Some("north"));
+    }
+
+    #[test]
+    fn test_compass_directions() {
+        assert_eq!(compass_direction(0.0), "north");
+        assert_eq!(compass_direction(350.0), "north");
+        assert_eq!(compass_direction(50.0), "north-east");
+        assert_eq!(compass_direction(180.0), "south");
+        assert_eq!(compass_direction(290.0), "west");
+    }
+}
@@ -0,0 +1,133 @@
+use crate::configuration::RateLimitConfiguration;
+use crate::error::NearbyError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Above that many keys, we forget the ones that have all their tokens back.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/**
+ * Token buckets : each key can send `burst` requests at once, then gets
+ * `per_minute` new tokens per minute. Keys are things like "hash:..." or
+ * "ip:...", so a client has to change both to bypass the limit.
+ * Buckets are kept in memory, so each server instance has its own limits.
+ */
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    burst: f64,
+    per_second: f64,
+    /// What is limited, for error messages.
+    what: &'static str,
+}
+
+/**
+ * Limits verification code requests, apart from nearby searches.
+ */
+#[derive(Clone)]
+pub struct VerificationCodeLimiter(pub RateLimiter);
+
+impl RateLimiter {
+    pub fn new(configuration: &RateLimitConfiguration) -> Self {
+        return RateLimiter::with_limits(
+            configuration.nearby_burst,
+            configuration.nearby_per_minute,
+            "nearby searches",
+        );
+    }
+
+    fn with_limits(burst: u32, per_minute: u32, what: &'static str) -> Self {
+        RateLimiter {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            burst: burst as f64,
+            per_second: per_minute as f64 / 60.0,
+            what,
+        }
+    }
+
+    /**
+     * Take a token for `key`, or fail if it has none left.
+     */
+    pub fn check(&self, key: &str, now: Instant) -> Result<(), NearbyError> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| NearbyError::Internal(String::from("Rate limiter is poisoned")))?;
+        if buckets.len() > MAX_TRACKED_KEYS {
+            let (burst, per_second) = (self.burst, self.per_second);
+            buckets.retain(|_, bucket| refilled(bucket, now, burst, per_second) < burst);
+        }
+        let bucket = buckets.entry(String::from(key)).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        bucket.tokens = refilled(bucket, now, self.burst, self.per_second);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return Err(NearbyError::TooManyRequests(std::format!(
+                "Too many {}, try again later",
+                self.what
+            )));
+        }
+        bucket.tokens -= 1.0;
+        return Ok(());
+    }
+}
+
+impl VerificationCodeLimiter {
+    pub fn new(configuration: &RateLimitConfiguration) -> Self {
+        return VerificationCodeLimiter(RateLimiter::with_limits(
+            configuration.verification_code_burst,
+            configuration.verification_code_per_minute,
+            "verification codes",
+        ));
+    }
+}
+
+fn refilled(bucket: &Bucket, now: Instant, burst: f64, per_second: f64) -> f64 {
+    let elapsed = now
+        .saturating_duration_since(bucket.updated_at)
+        .as_secs_f64();
+    return (bucket.tokens + elapsed * per_second).min(burst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_keys_are_limited_then_refilled() {
+        let limiter = RateLimiter::new(&RateLimitConfiguration {
+            nearby_burst: 2,
+            nearby_per_minute: 6,
+            ..RateLimitConfiguration::default()
+        });
+        let now = Instant::now();
+        assert!(limiter.check("hash:Peppa", now).is_ok());
+        assert!(limiter.check("hash:Peppa", now).is_ok());
+        assert!(matches!(
+            limiter.check("hash:Peppa", now),
+            Err(NearbyError::TooManyRequests(_))
+        ));
+        // Others are not limited :
+        assert!(limiter.check("hash:Rebecca", now).is_ok());
+
+        // One token every 10 seconds :
+        assert!(limiter
+            .check("hash:Peppa", now + Duration::from_secs(5))
+            .is_err());
+        assert!(limiter
+            .check("hash:Peppa", now + Duration::from_secs(16))
+            .is_ok());
+        assert!(limiter
+            .check("hash:Peppa", now + Duration::from_secs(17))
+            .is_err());
+    }
+}
@@ -1 +1,31 @@
-pub mod user_available;
\ No newline at end of file
+use crate::error::NearbyError;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+pub mod devices;
+pub mod matches;
+pub mod user_available;
+
+/**
+ * Malformed bodies and query strings get the same JSON errors as the rest.
+ */
+pub fn configure_payload_errors(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(
+        web::JsonConfig::default()
+            .error_handler(|err, _| NearbyError::Validation(err.to_string()).into()),
+    )
+    .app_data(
+        web::QueryConfig::default()
+            .error_handler(|err, _| NearbyError::Validation(err.to_string()).into()),
+    );
+}
+
+/**
+ * Answer to requests that don't match any route, so they also get a JSON error.
+ */
+pub async fn not_found(request: HttpRequest) -> Result<HttpResponse, NearbyError> {
+    return Err(NearbyError::NotFound(std::format!(
+        "No route for {} {}",
+        request.method(),
+        request.path()
+    )));
+}
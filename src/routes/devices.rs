@@ -0,0 +1,306 @@
+use crate::authentication::{middleware::AuthenticatedUser, token::TokenSigner};
+use crate::error::NearbyError;
+use crate::hashing::PhoneHasher;
+use crate::models::device;
+use crate::notification::push_tokens::{PushToken, PushTokens};
+use crate::rate_limiter::VerificationCodeLimiter;
+use crate::verification::{self, sms_sender::SmsSender, verification_codes::VerificationCodes};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use std::time::Instant;
+
+pub fn configure<P: SmsSender>(cfg: &mut web::ServiceConfig) {
+    cfg.route(
+        "/devices/verification_code",
+        web::post().to(request_verification_code::<P>),
+    )
+    .route("/devices", web::post().to(register_device))
+    .route("/devices/push_token", web::post().to(register_push_token));
+}
+
+/**
+ * Send a one time code by SMS, to check the device owns this phone number.
+ * Requests are limited per phone number and per IP address, so nobody can send
+ * SMS in a loop.
+ */
+pub async fn request_verification_code<P: SmsSender>(
+    codes: web::Data<VerificationCodes>,
+    sms_sender: web::Data<P>,
+    limiter: web::Data<VerificationCodeLimiter>,
+    request: web::Json<device::VerificationCodeRequest>,
+    req: HttpRequest,
+) -> Result<HttpResponse, NearbyError> {
+    let phone_number = parse_phone_number(&request.phone_number)?;
+    let now = Instant::now();
+    limiter
+        .0
+        .check(&std::format!("phone:{}", phone_number), now)?;
+    // We don't trust X-Forwarded-For, anyone can set it :
+    let ip = req
+        .peer_addr()
+        .map(|address| address.ip().to_string())
+        .unwrap_or_default();
+    limiter.0.check(&std::format!("ip:{}", ip), now)?;
+    let (code, expires_at) = codes.create(&phone_number, Utc::now())?;
+    sms_sender
+        .send(
+            &phone_number,
+            &std::format!("Your nearby verification code is {}", code),
+        )
+        .await?;
+    return Ok(HttpResponse::Ok().json(device::VerificationCodeSent { expires_at }));
+}
+
+/**
+ * Give a device a token bound to its phone number hash, once it proved it owns
+ * the phone number.
+ */
+pub async fn register_device(
+    signer: web::Data<TokenSigner>,
+    codes: web::Data<VerificationCodes>,
+    registration: web::Json<device::DeviceRegistration>,
+) -> Result<HttpResponse, NearbyError> {
+    if registration.device_id.is_empty() {
+        return Err(NearbyError::Validation(String::from(
+            "device_id must not be empty",
+        )));
+    }
+    let phone_number = parse_phone_number(&registration.phone_number)?;
+    let now = Utc::now();
+    codes.confirm(&phone_number, &registration.verification_code, now)?;
+
+    let phone_number_hash = verification::phone_number_hash(&phone_number);
+    println!(
+        "Registering device {:0} for User Phone : {:1}",
+        registration.device_id, phone_number_hash
+    );
+    let token = signer.issue(&phone_number_hash, &registration.device_id, now);
+    return Ok(HttpResponse::Ok().json(device::DeviceToken {
+        phone_number_hash,
+        token,
+        expires_at: now + signer.validity(),
+    }));
+}
+
+/**
+ * Tell where to send notifications when a match appears.
+ */
+pub async fn register_push_token(
+    push_tokens: web::Data<PushTokens>,
+    hasher: web::Data<PhoneHasher>,
+    authenticated: AuthenticatedUser,
+    registration: web::Json<device::PushTokenRegistration>,
+) -> Result<HttpResponse, NearbyError> {
+    if registration.token.is_empty() {
+        return Err(NearbyError::Validation(String::from(
+            "token must not be empty",
+        )));
+    }
+    println!(
+        "Registering push token of device {:0} for User Phone : {:1}",
+        authenticated.device_id, authenticated.phone_number_hash
+    );
+    let registration = registration.into_inner();
+    push_tokens
+        .register(
+            &hasher.all_keyed(&authenticated.phone_number_hash),
+            PushToken {
+                platform: registration.platform,
+                token: registration.token,
+            },
+        )
+        .await?;
+    return Ok(HttpResponse::Ok().finish());
+}
+
+fn parse_phone_number(phone_number: &str) -> Result<String, NearbyError> {
+    return verification::normalize_phone_number(phone_number).ok_or_else(|| {
+        NearbyError::Validation(String::from(
+            "phone_number must be in international format (+33612345678)",
+        ))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::middleware::Authentication;
+    use crate::configuration::{RateLimitConfiguration, VerificationConfiguration};
+    use crate::error::ErrorBody;
+    use crate::notification::payloads::Platform;
+    use crate::verification::sms_sender::FileSmsSender;
+    use actix_web::{http, test, App};
+    use chrono::Duration;
+
+    fn test_sms_sender(name: &str) -> FileSmsSender {
+        let path = std::env::temp_dir().join(std::format!(
+            "nearby-sms-{}-{}.log",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        return FileSmsSender::new(Some(path));
+    }
+
+    macro_rules! init_app {
+        ($sms_sender:expr) => {
+            test::init_service(
+                App::new()
+                    .data(TokenSigner::new(b"test secret", Duration::hours(1)))
+                    .data(VerificationCodes::new(&VerificationConfiguration::default()))
+                    .data(VerificationCodeLimiter::new(
+                        &RateLimitConfiguration::default(),
+                    ))
+                    .data($sms_sender)
+                    .configure(crate::routes::configure_payload_errors)
+                    .configure(configure::<FileSmsSender>),
+            )
+            .await
+        };
+    }
+
+    #[actix_rt::test]
+    async fn test_device_registration_with_sms_code() {
+        let sms_sender = test_sms_sender("registration");
+        let mut app = init_app!(sms_sender.clone());
+
+        let req = test::TestRequest::post()
+            .uri("/devices/verification_code")
+            .set_json(&device::VerificationCodeRequest {
+                phone_number: String::from("+33 6 12 34 56 78"),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let message = sms_sender
+            .last_message_to("+33612345678")
+            .expect("No SMS sent");
+        let code = message.rsplit(' ').next().expect("No code in SMS");
+
+        let req = test::TestRequest::post()
+            .uri("/devices")
+            .set_json(&device::DeviceRegistration {
+                phone_number: String::from("+33612345678"),
+                verification_code: String::from(code),
+                device_id: String::from("peppa-phone"),
+            })
+            .to_request();
+        let resp: device::DeviceToken = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            resp.phone_number_hash,
+            verification::phone_number_hash("+33612345678")
+        );
+        let claims = TokenSigner::new(b"test secret", Duration::hours(1))
+            .verify(&resp.token, Utc::now())
+            .expect("Invalid token");
+        assert_eq!(claims.sub, resp.phone_number_hash);
+    }
+
+    #[actix_rt::test]
+    async fn test_device_registration_needs_the_right_code() {
+        let sms_sender = test_sms_sender("wrong-code");
+        let mut app = init_app!(sms_sender.clone());
+
+        let req = test::TestRequest::post()
+            .uri("/devices/verification_code")
+            .set_json(&device::VerificationCodeRequest {
+                phone_number: String::from("+33612345678"),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        // Nobody asked a code for this number :
+        let req = test::TestRequest::post()
+            .uri("/devices")
+            .set_json(&device::DeviceRegistration {
+                phone_number: String::from("+33700000000"),
+                verification_code: String::from("123456"),
+                device_id: String::from("evil-phone"),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/devices/verification_code")
+            .set_json(&device::VerificationCodeRequest {
+                phone_number: String::from("not a number"),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let body: ErrorBody = test::read_body_json(resp).await;
+        assert_eq!(body.code, "VALIDATION");
+    }
+
+    #[actix_rt::test]
+    async fn test_push_tokens_are_registered_for_the_token_owner() {
+        let signer = TokenSigner::new(b"test secret", Duration::hours(1));
+        let push_tokens = PushTokens::new();
+        let hasher = PhoneHasher::with_random_key();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Authentication::new(signer.clone()))
+                .data(push_tokens.clone())
+                .data(hasher.clone())
+                .configure(configure::<FileSmsSender>),
+        )
+        .await;
+        let registration = device::PushTokenRegistration {
+            platform: Platform::Fcm,
+            token: String::from("peppa-fcm-token"),
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/devices/push_token")
+            .set_json(&registration)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+        let token = signer.issue("Peppa", "peppa-phone", Utc::now());
+        let req = test::TestRequest::post()
+            .header("authorization", std::format!("Bearer {}", token))
+            .uri("/devices/push_token")
+            .set_json(&registration)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(
+            push_tokens
+                .get(&hasher.keyed("Peppa"))
+                .await
+                .expect("Can't read push tokens"),
+            Some(PushToken {
+                platform: Platform::Fcm,
+                token: String::from("peppa-fcm-token"),
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_verification_codes_are_rate_limited() {
+        let sms_sender = test_sms_sender("rate-limit");
+        let mut app = init_app!(sms_sender.clone());
+        let burst = RateLimitConfiguration::default().verification_code_burst;
+
+        // Different numbers, from the same address :
+        for index in 0..=burst {
+            let req = test::TestRequest::post()
+                .uri("/devices/verification_code")
+                .set_json(&device::VerificationCodeRequest {
+                    phone_number: std::format!("+3361234567{}", index),
+                })
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            let expected = if index < burst {
+                http::StatusCode::OK
+            } else {
+                http::StatusCode::TOO_MANY_REQUESTS
+            };
+            assert_eq!(resp.status(), expected);
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use crate::authentication::middleware::AuthenticatedUser;
+use crate::database::availability_store::AvailabilityStore;
+use crate::error::NearbyError;
+use crate::events::match_broker::{MatchBroker, MatchEvent, Subscribe, Unsubscribe};
+use crate::hashing::PhoneHasher;
+use crate::routes::user_available::get_stored_user;
+use actix::prelude::*;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use actix_web_actors::ws;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Comments are sent that often on event streams, to detect closed connections.
+const SSE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Identifies subscriptions in the broker.
+static NEXT_SUBSCRIPTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub fn configure<S: AvailabilityStore>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/matches/ws", web::get().to(match_websocket::<S>))
+        .route("/matches/events", web::get().to(match_event_stream::<S>));
+}
+
+/**
+ * Instead of polling nearby searches, clients can open a websocket and receive
+ * a JSON `MatchEvent` each time a contact becomes available nearby, moves, or
+ * stops being available.
+ */
+pub async fn match_websocket<S: AvailabilityStore>(
+    database: web::Data<S>,
+    hasher: web::Data<PhoneHasher>,
+    broker: web::Data<Addr<MatchBroker>>,
+    authenticated: AuthenticatedUser,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, NearbyError> {
+    println!(
+        "User Phone : {:0} listens to matches",
+        authenticated.phone_number_hash
+    );
+    let subscription = create_subscription(
+        database.get_ref(),
+        &hasher,
+        &authenticated,
+        broker.get_ref().clone(),
+    )
+    .await?;
+    let session = MatchSession {
+        subscription: subscription.clone(),
+    };
+    let (session, response) = ws::start_with_addr(session, &req, payload).map_err(|err| {
+        NearbyError::Validation(std::format!("Not a websocket request : {}", err))
+    })?;
+    // Before answering, so the client doesn't miss the first events :
+    subscription.subscribe(session.recipient()).await?;
+    return Ok(response);
+}
+
+/**
+ * Same events as the websocket, as Server-Sent Events, for clients that can't
+ * use websockets.
+ */
+pub async fn match_event_stream<S: AvailabilityStore>(
+    database: web::Data<S>,
+    hasher: web::Data<PhoneHasher>,
+    broker: web::Data<Addr<MatchBroker>>,
+    authenticated: AuthenticatedUser,
+) -> Result<HttpResponse, NearbyError> {
+    println!(
+        "User Phone : {:0} listens to match events",
+        authenticated.phone_number_hash
+    );
+    let subscription = create_subscription(
+        database.get_ref(),
+        &hasher,
+        &authenticated,
+        broker.get_ref().clone(),
+    )
+    .await?;
+    let (sender, receiver) = mpsc::unbounded();
+    let session = MatchEventSession {
+        subscription: subscription.clone(),
+        sender,
+    }
+    .start();
+    subscription.subscribe(session.recipient()).await?;
+    return Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("cache-control", "no-cache")
+        .streaming(receiver.map(Ok::<Bytes, NearbyError>)));
+}
+
+/**
+ * Everything needed to subscribe to the match events of a user.
+ */
+#[derive(Clone)]
+pub struct Subscription {
+    pub id: usize,
+    pub phone_number_hashes: Vec<String>,
+    pub location: Option<(f64, f64)>,
+    pub broker: Addr<MatchBroker>,
+}
+
+impl Subscription {
+    pub async fn subscribe(&self, recipient: Recipient<MatchEvent>) -> Result<(), NearbyError> {
+        return self
+            .broker
+            .send(Subscribe {
+                id: self.id,
+                phone_number_hashes: self.phone_number_hashes.clone(),
+                location: self.location,
+                recipient,
+            })
+            .await
+            .map_err(|err| NearbyError::Internal(std::format!("Match broker is dead : {}", err)));
+    }
+
+    fn unsubscribe(&self) {
+        self.broker.do_send(Unsubscribe { id: self.id });
+    }
+}
+
+pub async fn create_subscription<S: AvailabilityStore>(
+    database: &S,
+    hasher: &PhoneHasher,
+    authenticated: &AuthenticatedUser,
+    broker: Addr<MatchBroker>,
+) -> Result<Subscription, NearbyError> {
+    // When the user is not available yet, events start when it posts a location :
+    let location = get_stored_user(database, hasher, &authenticated.phone_number_hash)
+        .await?
+        .map(|user| (user.latitude, user.longitude));
+    return Ok(Subscription {
+        id: NEXT_SUBSCRIPTION_ID.fetch_add(1, Ordering::Relaxed),
+        phone_number_hashes: hasher.all_keyed(&authenticated.phone_number_hash),
+        location,
+        broker,
+    });
+}
+
+/**
+ * One websocket : forwards match events from the broker to the client.
+ */
+struct MatchSession {
+    subscription: Subscription,
+}
+
+impl Actor for MatchSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.subscription.unsubscribe();
+    }
+}
+
+impl Handler<MatchEvent> for MatchSession {
+    type Result = ();
+    fn handle(&mut self, event: MatchEvent, ctx: &mut Self::Context) {
+        match serde_json::to_string(&event) {
+            Ok(json) => ctx.text(json),
+            Err(err) => println!("Can't serialize match event : {}", err),
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MatchSession {
+    fn handle(&mut self, message: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match message {
+            Ok(ws::Message::Ping(message)) => ctx.pong(&message),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            // Clients have nothing to say :
+            Ok(_) => {}
+            Err(err) => {
+                println!("Websocket error : {}", err);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+/**
+ * One Server-Sent Events stream : each match event is sent as a `data:` line.
+ */
+struct MatchEventSession {
+    subscription: Subscription,
+    /// Sent as the response body.
+    sender: mpsc::UnboundedSender<Bytes>,
+}
+
+impl MatchEventSession {
+    fn write(&mut self, data: String, ctx: &mut Context<Self>) {
+        if self.sender.unbounded_send(Bytes::from(data)).is_err() {
+            // The client is gone :
+            ctx.stop();
+        }
+    }
+}
+
+impl Actor for MatchEventSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        // Without events, this is how we notice clients that left :
+        ctx.run_interval(SSE_KEEP_ALIVE_INTERVAL, |this, ctx| {
+            this.write(String::from(": keep-alive\n\n"), ctx);
+        });
+    }
+
+    fn stopped(&mut self, _: &mut Context<Self>) {
+        self.subscription.unsubscribe();
+    }
+}
+
+impl Handler<MatchEvent> for MatchEventSession {
+    type Result = ();
+    fn handle(&mut self, event: MatchEvent, ctx: &mut Context<Self>) {
+        match serde_json::to_string(&event) {
+            Ok(json) => self.write(std::format!("data: {}\n\n", json), ctx),
+            Err(err) => println!("Can't serialize match event : {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{middleware::Authentication, token::TokenSigner};
+    use crate::configuration::{
+        HashKeyConfiguration, HashingConfiguration, PrivacyConfiguration, SearchConfiguration,
+    };
+    use crate::database::in_memory_store::InMemoryStore;
+    use crate::events::{
+        event_bus::EventBus, match_broker::MatchEventKind, publishing_store::PublishingStore,
+    };
+    use crate::models::user;
+    use actix_web::{test, App};
+    use chrono::{DateTime, Duration, Utc};
+    use futures::SinkExt;
+
+    fn available(hasher: &PhoneHasher, phone_number_hash: &str, contacts: &[&str]) -> user::User {
+        return user::User {
+            phone_number_hash: hasher.keyed(phone_number_hash),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: DateTime::from(Utc::now() + Duration::hours(1)),
+            location_precision: user::LocationPrecision::Approximate,
+            status_message: None,
+            contacts_phone_number_hash: contacts
+                .iter()
+                .flat_map(|contact| hasher.all_keyed(contact))
+                .collect(),
+        };
+    }
+
+    /**
+     * Peppa is available, the returned token is Peppa's.
+     */
+    async fn start_server() -> (
+        test::TestServer,
+        String,
+        PhoneHasher,
+        PublishingStore<InMemoryStore>,
+    ) {
+        let signer = TokenSigner::new(b"test secret", Duration::hours(1));
+        let hasher = PhoneHasher::new(&HashingConfiguration {
+            current_version: 1,
+            keys: vec![HashKeyConfiguration {
+                version: 1,
+                secret: String::from("test secret"),
+            }],
+        })
+        .expect("Can't create hasher");
+        let bus = EventBus::new();
+        let database = PublishingStore::new(InMemoryStore::new(), bus.clone());
+        let broker = MatchBroker::new(
+            bus,
+            SearchConfiguration::default().default_radius_m,
+            PrivacyConfiguration::default(),
+        )
+        .start();
+        database
+            .set_user_available(&available(&hasher, "Peppa", &[]))
+            .await
+            .expect("Can't add user");
+
+        let token = signer.issue("Peppa", "peppa-phone", Utc::now());
+        let server = {
+            let (hasher, database) = (hasher.clone(), database.clone());
+            test::start(move || {
+                App::new()
+                    .wrap(Authentication::new(signer.clone()))
+                    .data(database.clone())
+                    .data(hasher.clone())
+                    .data(broker.clone())
+                    .configure(configure::<PublishingStore<InMemoryStore>>)
+            })
+        };
+        return (server, token, hasher, database);
+    }
+
+    /**
+     * Rebecca, who picked Peppa, comes and leaves.
+     */
+    async fn rebecca_comes_and_leaves(
+        hasher: &PhoneHasher,
+        database: &PublishingStore<InMemoryStore>,
+    ) {
+        database
+            .set_user_available(&available(hasher, "Rebecca", &["Peppa"]))
+            .await
+            .expect("Can't add user");
+        database
+            .remove_user(&hasher.keyed("Rebecca"))
+            .await
+            .expect("Can't remove user");
+    }
+
+    fn check_event(event: &MatchEvent, expected: MatchEventKind, hasher: &PhoneHasher) {
+        assert_eq!(event.event, expected);
+        assert_eq!(event.contact.phone_number_hash, hasher.keyed("Rebecca"));
+        // Rebecca's location is approximate :
+        assert!(event.contact.distance.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_matches_are_streamed_over_websocket() {
+        let (server, token, hasher, database) = start_server().await;
+        let (_, mut framed) = awc::Client::new()
+            .ws(server.url("/matches/ws"))
+            .header("authorization", std::format!("Bearer {}", token))
+            .connect()
+            .await
+            .expect("Can't open websocket");
+
+        framed
+            .send(ws::Message::Ping(Bytes::from_static(b"hello")))
+            .await
+            .expect("Can't ping");
+        assert_eq!(
+            framed.next().await.expect("No pong").expect("Bad frame"),
+            ws::Frame::Pong(Bytes::from_static(b"hello"))
+        );
+
+        rebecca_comes_and_leaves(&hasher, &database).await;
+
+        for expected in [MatchEventKind::Available, MatchEventKind::Unavailable] {
+            let frame = framed.next().await.expect("No event").expect("Bad frame");
+            let text = match frame {
+                ws::Frame::Text(text) => text,
+                _ => panic!("Events must be sent as text"),
+            };
+            let event: MatchEvent = serde_json::from_slice(&text).expect("Invalid event");
+            check_event(&event, expected, &hasher);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_matches_are_streamed_as_server_sent_events() {
+        let (server, token, hasher, database) = start_server().await;
+        let mut response = awc::Client::new()
+            .get(server.url("/matches/events"))
+            .header("authorization", std::format!("Bearer {}", token))
+            .send()
+            .await
+            .expect("Can't open event stream");
+        assert_eq!(
+            response
+                .headers()
+                .get("content-type")
+                .expect("No content type"),
+            "text/event-stream"
+        );
+
+        rebecca_comes_and_leaves(&hasher, &database).await;
+
+        let mut received = String::new();
+        while received.matches("\n\n").count() < 2 {
+            let chunk = response
+                .next()
+                .await
+                .expect("Stream ended")
+                .expect("Bad chunk");
+            received.push_str(std::str::from_utf8(&chunk).expect("Events must be UTF-8"));
+        }
+        let events: Vec<MatchEvent> = received
+            .split_terminator("\n\n")
+            .map(|event| {
+                let json = event.strip_prefix("data: ").expect("Events must be data");
+                return serde_json::from_str(json).expect("Invalid event");
+            })
+            .collect();
+        check_event(&events[0], MatchEventKind::Available, &hasher);
+        check_event(&events[1], MatchEventKind::Unavailable, &hasher);
+    }
+}
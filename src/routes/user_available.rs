@@ -1,64 +1,356 @@
-use crate::database::database_interface::DataBaseInterface;
-use crate::models::user;
-use actix_web::{
-    error::{Error, ErrorInternalServerError},
-    web, HttpResponse, Result,
-};
-
-pub async fn user_available(
-    database: web::Data<DataBaseInterface>,
+use crate::authentication::middleware::AuthenticatedUser;
+use crate::configuration::{PrivacyConfiguration, SearchConfiguration, ValidationConfiguration};
+use crate::database::{availability_store::AvailabilityStore, in_memory_store};
+use crate::error::NearbyError;
+use crate::hashing::PhoneHasher;
+use crate::models::{nearby_query::NearbyQuery, nearby_request, user};
+use crate::privacy;
+use crate::rate_limiter::RateLimiter;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use std::time::Instant;
+
+/**
+ * Register the routes of this module, so the server and the tests use the same ones.
+ */
+pub fn configure<S: AvailabilityStore>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/user_available", web::post().to(user_available::<S>))
+        .route(
+            "/user_available/{phone_number_hash}",
+            web::delete().to(user_unavailable::<S>),
+        )
+        .route(
+            "/contacts_availables_nearby",
+            web::get().to(get_nearby_friends::<S>),
+        )
+        .route(
+            "/contacts_availables_nearby",
+            web::post().to(post_nearby_friends::<S>),
+        )
+        // Same as the GET with a JSON body, for clients that can't send one :
+        .route(
+            "/compat/contacts_availables_nearby",
+            web::get().to(get_nearby_friends_legacy::<S>),
+        );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn user_available<S: AvailabilityStore>(
+    database: web::Data<S>,
+    rules: web::Data<ValidationConfiguration>,
+    privacy_configuration: web::Data<PrivacyConfiguration>,
+    hasher: web::Data<PhoneHasher>,
+    limiter: web::Data<RateLimiter>,
+    authenticated: AuthenticatedUser,
     user: web::Json<user::User>,
-) -> Result<HttpResponse, Error> {
+    req: HttpRequest,
+) -> Result<HttpResponse, NearbyError> {
+    // Without a limit, keyed contacts would let anyone hash with our key :
+    check_rate_limit(&limiter, &authenticated, &req)?;
+    authenticated.check_is(&user.phone_number_hash)?;
     println!(
-        "User Phone : {:0}, available until : {:1}",
-        user.phone_number_hash, user.available_until
+        "User Phone : {:0} (device {:1}), available until : {:2}",
+        user.phone_number_hash, authenticated.device_id, user.available_until
     );
-    database
-        .set_user_available(&user)
-        .await
-        .map_err(|err| ErrorInternalServerError(err.message))?;
-    return Ok(HttpResponse::Ok().finish());
+    user.validate(&rules, Utc::now())
+        .map_err(NearbyError::InvalidFields)?;
+    // The user may still be stored with an older key :
+    for old_hash in hasher.all_keyed(&user.phone_number_hash).iter().skip(1) {
+        database.remove_user(old_hash).await?;
+    }
+    let stored_user = hasher.keyed_user(&privacy::blur_user(&user, &privacy_configuration));
+    // Matches are notified in the background, from the stored events :
+    database.set_user_available(&stored_user).await?;
+    return Ok(HttpResponse::Ok().json(user::UserAvailable {
+        keyed_contacts: hasher.keyed_contacts(&user),
+    }));
 }
 
-pub async fn get_nearby_friends(
-    database: web::Data<DataBaseInterface>,
-    user: web::Json<user::User>,
-) -> Result<HttpResponse, Error> {
+/**
+ * Stop being available now, without waiting for `available_until`.
+ */
+pub async fn user_unavailable<S: AvailabilityStore>(
+    database: web::Data<S>,
+    hasher: web::Data<PhoneHasher>,
+    authenticated: AuthenticatedUser,
+    phone_number_hash: web::Path<String>,
+) -> Result<HttpResponse, NearbyError> {
+    authenticated.check_is(&phone_number_hash)?;
+    println!(
+        "User Phone : {:0} is no longer available",
+        phone_number_hash
+    );
+    let mut removed = false;
+    for keyed_hash in hasher.all_keyed(&phone_number_hash) {
+        removed |= database.remove_user(&keyed_hash).await?;
+    }
+    return Ok(HttpResponse::Ok().json(user::UserRemoved { removed }));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn get_nearby_friends<S: AvailabilityStore>(
+    database: web::Data<S>,
+    search: web::Data<SearchConfiguration>,
+    privacy_configuration: web::Data<PrivacyConfiguration>,
+    hasher: web::Data<PhoneHasher>,
+    limiter: web::Data<RateLimiter>,
+    authenticated: AuthenticatedUser,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, NearbyError> {
+    check_rate_limit(&limiter, &authenticated, &req)?;
+    let query = if req.query_string().is_empty() {
+        // Old clients send a full user in the body, without a query string :
+        let request: nearby_request::NearbyRequest = serde_json::from_slice(&body)
+            .map_err(|err| NearbyError::Validation(err.to_string()))?;
+        NearbyQuery::from(request)
+    } else {
+        web::Query::<NearbyQuery>::from_query(req.query_string())
+            .map_err(|err| NearbyError::Validation(err.to_string()))?
+            .into_inner()
+    };
+    return find_nearby_friends(
+        database.get_ref(),
+        &search,
+        &privacy_configuration,
+        &hasher,
+        &authenticated,
+        &query,
+    )
+    .await;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn post_nearby_friends<S: AvailabilityStore>(
+    database: web::Data<S>,
+    search: web::Data<SearchConfiguration>,
+    privacy_configuration: web::Data<PrivacyConfiguration>,
+    hasher: web::Data<PhoneHasher>,
+    limiter: web::Data<RateLimiter>,
+    authenticated: AuthenticatedUser,
+    query: web::Json<NearbyQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, NearbyError> {
+    check_rate_limit(&limiter, &authenticated, &req)?;
+    return find_nearby_friends(
+        database.get_ref(),
+        &search,
+        &privacy_configuration,
+        &hasher,
+        &authenticated,
+        &query,
+    )
+    .await;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn get_nearby_friends_legacy<S: AvailabilityStore>(
+    database: web::Data<S>,
+    search: web::Data<SearchConfiguration>,
+    privacy_configuration: web::Data<PrivacyConfiguration>,
+    hasher: web::Data<PhoneHasher>,
+    limiter: web::Data<RateLimiter>,
+    authenticated: AuthenticatedUser,
+    request: web::Json<nearby_request::NearbyRequest>,
+    req: HttpRequest,
+) -> Result<HttpResponse, NearbyError> {
+    check_rate_limit(&limiter, &authenticated, &req)?;
+    let query = NearbyQuery::from(request.into_inner());
+    return find_nearby_friends(
+        database.get_ref(),
+        &search,
+        &privacy_configuration,
+        &hasher,
+        &authenticated,
+        &query,
+    )
+    .await;
+}
+
+/**
+ * Searching from many places lets anyone find where a friend is, and posting
+ * many contacts gives their keyed hashes, so we limit searches and posted
+ * availabilities per phone number hash and per IP address.
+ */
+fn check_rate_limit(
+    limiter: &RateLimiter,
+    authenticated: &AuthenticatedUser,
+    req: &HttpRequest,
+) -> Result<(), NearbyError> {
+    let now = Instant::now();
+    limiter.check(
+        &std::format!("hash:{}", authenticated.phone_number_hash),
+        now,
+    )?;
+    // We don't trust X-Forwarded-For, anyone can set it :
+    let ip = req
+        .peer_addr()
+        .map(|address| address.ip().to_string())
+        .unwrap_or_default();
+    return limiter.check(&std::format!("ip:{}", ip), now);
+}
+
+async fn find_nearby_friends<S: AvailabilityStore>(
+    database: &S,
+    search: &SearchConfiguration,
+    privacy_configuration: &PrivacyConfiguration,
+    hasher: &PhoneHasher,
+    authenticated: &AuthenticatedUser,
+    query: &NearbyQuery,
+) -> Result<HttpResponse, NearbyError> {
+    authenticated.check_is(&query.phone_number_hash)?;
     println!(
-        "User Phone : {:0}, available until : {:1}",
-        user.phone_number_hash, user.available_until
+        "User Phone : {:0} looks for friends nearby",
+        query.phone_number_hash
     );
-    let available_contacts = database
+    query.validate().map_err(NearbyError::InvalidFields)?;
+    let max_distance_m = search
+        .radius_m(query.max_distance_m)
+        .map_err(NearbyError::Validation)?;
+    let limit = search.limit(query.limit).map_err(NearbyError::Validation)?;
+    check_declared_location(database, search, hasher, query).await?;
+    let mut available_contacts = database
         .get_contacts_available_nearby(
-            &user.phone_number_hash,
-            user.latitude,
-            user.longitude,
-            10_000f32, // TODO : Expose that to the API !
+            &hasher.keyed(&query.phone_number_hash),
+            query.latitude,
+            query.longitude,
+            max_distance_m,
+            limit,
+            query.mutual,
         )
-        .await
-        .map_err(|err| ErrorInternalServerError(err.message))?;
+        .await?;
+    for contact in available_contacts.iter_mut() {
+        privacy::blur_result(contact, privacy_configuration);
+    }
 
     return Ok(HttpResponse::Ok().json(available_contacts));
 }
 
+/**
+ * The user as stored, whatever the key its hash was stored with.
+ */
+pub async fn get_stored_user<S: AvailabilityStore>(
+    database: &S,
+    hasher: &PhoneHasher,
+    phone_number_hash: &str,
+) -> Result<Option<user::User>, NearbyError> {
+    for keyed_hash in hasher.all_keyed(phone_number_hash) {
+        if let Some(user) = database.get_user(&keyed_hash).await? {
+            return Ok(Some(user));
+        }
+    }
+    return Ok(None);
+}
+
+/**
+ * Users can only search from where they said they are : they must be available,
+ * and close to the location they posted.
+ */
+async fn check_declared_location<S: AvailabilityStore>(
+    database: &S,
+    search: &SearchConfiguration,
+    hasher: &PhoneHasher,
+    query: &NearbyQuery,
+) -> Result<(), NearbyError> {
+    let declared = get_stored_user(database, hasher, &query.phone_number_hash)
+        .await?
+        .ok_or_else(|| {
+            NearbyError::Forbidden(String::from(
+                "You must be available to look for friends nearby",
+            ))
+        })?;
+    let distance = in_memory_store::haversine_distance_m(
+        declared.latitude,
+        declared.longitude,
+        query.latitude,
+        query.longitude,
+    );
+    if distance > search.max_distance_from_declared_m {
+        return Err(NearbyError::Forbidden(String::from(
+            "You can only look for friends from your declared location",
+        )));
+    }
+    return Ok(());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::authentication::{middleware::Authentication, token::TokenSigner};
+    use crate::configuration::{
+        DatabaseConfiguration, HashKeyConfiguration, HashingConfiguration, RateLimitConfiguration,
+    };
+    use crate::database::database_interface::DataBaseInterface;
+    use crate::database::in_memory_store::InMemoryStore;
+    use crate::error::ErrorBody;
     use actix_web::{http, test, App};
-    use chrono::DateTime;
+    use chrono::{DateTime, Duration, FixedOffset};
     use std::string::String;
     use user::LocalizedUser;
 
+    fn test_signer() -> TokenSigner {
+        TokenSigner::new(b"test secret", Duration::hours(1))
+    }
+
+    fn test_hasher() -> PhoneHasher {
+        PhoneHasher::new(&HashingConfiguration {
+            current_version: 1,
+            keys: vec![HashKeyConfiguration {
+                version: 1,
+                secret: String::from("test secret"),
+            }],
+        })
+        .expect("Can't create hasher")
+    }
+
+    /**
+     * Authorization header of a device registered for this phone number hash.
+     */
+    fn bearer(phone_number_hash: &str) -> String {
+        let token = test_signer().issue(phone_number_hash, "test-device", Utc::now());
+        return std::format!("Bearer {}", token);
+    }
+
+    macro_rules! init_app {
+        ($store:ty, $database_interface:expr) => {{
+            let database_interface: $store = $database_interface;
+            test::init_service(
+                App::new()
+                    .wrap(Authentication::new(test_signer()))
+                    .data(database_interface)
+                    .data(SearchConfiguration::default())
+                    .data(ValidationConfiguration::default())
+                    .data(PrivacyConfiguration::default())
+                    .data(test_hasher())
+                    .data(RateLimiter::new(&RateLimitConfiguration::default()))
+                    .configure(crate::routes::configure_payload_errors)
+                    .configure(configure::<$store>),
+            )
+            .await
+        }};
+    }
+
     #[actix_rt::test]
     async fn test_full_scenario() {
-        let database_interface = DataBaseInterface::new().await.unwrap();
+        let database_interface = DataBaseInterface::new(&DatabaseConfiguration::default())
+            .await
+            .unwrap();
+        full_scenario(database_interface).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_full_scenario_in_memory() {
+        full_scenario(InMemoryStore::new()).await;
+    }
+
+    async fn full_scenario<S: AvailabilityStore>(database_interface: S) {
         database_interface.clear_database().await.unwrap();
         /*
          * Given :
          *
          *         Available Until       Friend Of      Location
          *  Peppa   22h00          Rebecca, Suzy, Pedro   43.0,6.0
-         *  Rebecca 21h00          Peppa                  43.0,6.0
+         *  Rebecca 21h00          Peppa                  43.002,6.0
          *  Suzy    21h00          Peppa                  44,5  // Too far !!
          *  Pedro   21h00          Suzy                   43.0,6.0
          *
@@ -70,44 +362,45 @@ mod tests {
          *
          *  Then it shoud return nothing.
          */
-        let mut app = test::init_service(
-            App::new()
-                .data(database_interface.clone())
-                .route("/user_available", web::post().to(user_available))
-                .route(
-                    "/contacts_availables_nearby",
-                    web::get().to(get_nearby_friends),
-                ),
-        )
-        .await;
+        let mut app = init_app!(S, database_interface.clone());
 
-        let nine_pm =
-            DateTime::parse_from_rfc3339("2021-05-21T21:00:00+00:00").expect("Can't parse date");
+        // Availabilities must be in the future, "9 pm" is in one hour from now :
+        let now = Utc::now();
+        let nine_pm: DateTime<FixedOffset> = DateTime::from(now + Duration::hours(1));
         let rebecca = user::User {
             phone_number_hash: String::from("Rebecca"),
-            latitude: 43.0000000,
+            latitude: 43.0020000,
             longitude: 6.000000,
             available_until: nine_pm,
+            location_precision: user::LocationPrecision::Exact,
+            status_message: Some(String::from("Free for a drink")),
             contacts_phone_number_hash: vec![String::from("Peppa")],
         };
 
         let req = test::TestRequest::post()
+            .header("authorization", bearer("Rebecca"))
             .header("content-type", "application/json")
             .uri("/user_available")
             .set_json(&rebecca)
             .to_request();
-        let resp = test::call_service(&mut app, req).await;
-        assert_eq!(resp.status(), http::StatusCode::OK);
+        let resp: user::UserAvailable = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            resp.keyed_contacts.get(&test_hasher().keyed("Peppa")),
+            Some(&String::from("Peppa"))
+        );
 
         let suzy = user::User {
             phone_number_hash: String::from("Suzy"),
             latitude: 44.0,
             longitude: 5.0,
             available_until: nine_pm,
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec![String::from("Peppa")],
         };
 
         let req = test::TestRequest::post()
+            .header("authorization", bearer("Suzy"))
             .header("content-type", "application/json")
             .uri("/user_available")
             .set_json(&suzy)
@@ -120,10 +413,13 @@ mod tests {
             latitude: 43.0,
             longitude: 6.0,
             available_until: nine_pm,
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec![String::from("Suzy")],
         };
 
         let req = test::TestRequest::post()
+            .header("authorization", bearer("Peppa"))
             .header("content-type", "application/json")
             .uri("/user_available")
             .set_json(&pedro)
@@ -135,8 +431,9 @@ mod tests {
             phone_number_hash: String::from("Peppa"),
             latitude: 43.0000000,
             longitude: 6.000000,
-            available_until: DateTime::parse_from_rfc3339("2021-05-21T22:00:00+00:00")
-                .expect("Can't parse date"),
+            available_until: DateTime::from(now + Duration::hours(2)),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
             contacts_phone_number_hash: vec![
                 String::from("Suzy"),
                 String::from("Rebecca"),
@@ -144,35 +441,313 @@ mod tests {
             ],
         };
         let req = test::TestRequest::get()
-            .header("content-type", "application/json")
-            .uri("/contacts_availables_nearby")
-            .set_json(&peppa)
+            .header("authorization", bearer("Peppa"))
+            .uri("/contacts_availables_nearby?phone_number_hash=Peppa&lat=43.0&lon=6.0")
             .to_request();
 
         let resp: Vec<LocalizedUser> = test::read_response_json(&mut app, req).await;
         assert_eq!(resp.len(), 1);
         assert_eq!(
-            resp.get(0)
+            resp.first()
                 .expect("Not enough nearby friends")
                 .phone_number_hash,
-            "Rebecca"
+            test_hasher().keyed("Rebecca")
         );
+        assert_eq!(resp[0].distance_bucket.as_deref(), Some("<500 m"));
+        // Enough to show "Rebecca, 200 m north, free until 21:00" :
+        assert_eq!(resp[0].direction.as_deref(), Some("north"));
+        assert_eq!(resp[0].status_message.as_deref(), Some("Free for a drink"));
+        // Stored with milliseconds by MongoDB :
+        assert_eq!(
+            resp[0].available_until.map(|until| until.timestamp()),
+            Some(nine_pm.timestamp())
+        );
+        assert!(resp[0].updated_at.is_some());
+
+        // Old clients still get the same answer, on both paths :
+        for uri in [
+            "/contacts_availables_nearby",
+            "/compat/contacts_availables_nearby",
+        ] {
+            let req = test::TestRequest::get()
+                .header("authorization", bearer("Peppa"))
+                .header("content-type", "application/json")
+                .uri(uri)
+                .set_json(&peppa)
+                .to_request();
+            let resp: Vec<LocalizedUser> = test::read_response_json(&mut app, req).await;
+            assert_eq!(resp.len(), 1);
+        }
+
+        // Rebecca doesn't want to go out anymore :
+        let req = test::TestRequest::delete()
+            .header("authorization", bearer("Rebecca"))
+            .uri("/user_available/Rebecca")
+            .to_request();
+        let resp: user::UserRemoved = test::read_response_json(&mut app, req).await;
+        assert!(resp.removed);
+        let req = test::TestRequest::get()
+            .header("authorization", bearer("Peppa"))
+            .uri("/contacts_availables_nearby?phone_number_hash=Peppa&lat=43.0&lon=6.0")
+            .to_request();
+        let resp: Vec<LocalizedUser> = test::read_response_json(&mut app, req).await;
+        assert_eq!(resp.len(), 0);
+        let req = test::TestRequest::delete()
+            .header("authorization", bearer("Rebecca"))
+            .uri("/user_available/Rebecca")
+            .to_request();
+        let resp: user::UserRemoved = test::read_response_json(&mut app, req).await;
+        assert!(!resp.removed);
 
         let deleted = database_interface
-            .remove_available_until(
-                DateTime::parse_from_rfc3339("2021-05-21T21:30:00+00:00")
-                    .expect("Can't parse date"),
-            )
+            .remove_available_until(DateTime::from(now + Duration::minutes(90)))
             .await
             .expect("Can't remove users");
-        assert_eq!(deleted, 3);
+        assert_eq!(deleted.len(), 2);
 
-        let req = test::TestRequest::get()
+        // Peppa must be available to search :
+        let req = test::TestRequest::post()
+            .header("authorization", bearer("Peppa"))
+            .uri("/user_available")
+            .set_json(&peppa)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let query = NearbyQuery {
+            phone_number_hash: peppa.phone_number_hash.clone(),
+            latitude: peppa.latitude,
+            longitude: peppa.longitude,
+            max_distance_m: None,
+            limit: None,
+            mutual: false,
+        };
+        let req = test::TestRequest::post()
+            .header("authorization", bearer("Peppa"))
             .header("content-type", "application/json")
             .uri("/contacts_availables_nearby")
-            .set_json(&peppa)
+            .set_json(&query)
             .to_request();
         let resp: Vec<LocalizedUser> = test::read_response_json(&mut app, req).await;
         assert_eq!(resp.len(), 0);
     }
+
+    #[actix_rt::test]
+    async fn test_nearby_search_parameters_are_validated() {
+        let database_interface = InMemoryStore::new();
+        let mut app = init_app!(InMemoryStore, database_interface.clone());
+
+        let peppa = user::User {
+            phone_number_hash: String::from("Peppa"),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: DateTime::from(Utc::now() + Duration::hours(1)),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![],
+        };
+        database_interface
+            .set_user_available(&test_hasher().keyed_user(&peppa))
+            .await
+            .expect("Can't add user");
+        for (max_distance_m, limit, expected_status) in [
+            ("2000", "10", http::StatusCode::OK),
+            ("1", "10", http::StatusCode::BAD_REQUEST),
+            ("1000000", "10", http::StatusCode::BAD_REQUEST),
+            ("2000", "0", http::StatusCode::BAD_REQUEST),
+        ] {
+            let req = test::TestRequest::get()
+                .header("authorization", bearer("Peppa"))
+                .uri(&std::format!(
+                    "/contacts_availables_nearby?phone_number_hash=Peppa&latitude=43.0&longitude=6.0&max_distance_m={}&limit={}",
+                    max_distance_m, limit
+                ))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), expected_status);
+        }
+
+        // NaN is never too far, and trigonometry brings 403 back to 43 :
+        for (latitude, longitude, field) in [
+            ("NaN", "6.0", "latitude"),
+            ("403.0", "6.0", "latitude"),
+            ("43.0", "-200.0", "longitude"),
+        ] {
+            let req = test::TestRequest::get()
+                .header("authorization", bearer("Peppa"))
+                .uri(&std::format!(
+                    "/contacts_availables_nearby?phone_number_hash=Peppa&latitude={}&longitude={}",
+                    latitude,
+                    longitude
+                ))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+            let body: ErrorBody = test::read_body_json(resp).await;
+            assert_eq!(body.fields[0].field, field);
+        }
+
+        let request = nearby_request::NearbyRequest {
+            user: peppa,
+            max_distance_m: Some(1f32),
+            limit: None,
+        };
+        let req = test::TestRequest::get()
+            .header("authorization", bearer("Peppa"))
+            .header("content-type", "application/json")
+            .uri("/compat/contacts_availables_nearby")
+            .set_json(&request)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        // Missing parameters are reported with the same JSON body :
+        let req = test::TestRequest::get()
+            .header("authorization", bearer("Peppa"))
+            .uri("/contacts_availables_nearby?phone_number_hash=Peppa")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let body: ErrorBody = test::read_body_json(resp).await;
+        assert_eq!(body.code, "VALIDATION");
+    }
+
+    #[actix_rt::test]
+    async fn test_nearby_searches_are_limited() {
+        let database_interface = InMemoryStore::new();
+        let mut app = init_app!(InMemoryStore, database_interface.clone());
+        let search = |latitude: f64| {
+            test::TestRequest::get()
+                .header("authorization", bearer("Peppa"))
+                .uri(&std::format!(
+                    "/contacts_availables_nearby?phone_number_hash=Peppa&lat={}&lon=6.0",
+                    latitude
+                ))
+                .to_request()
+        };
+
+        // Peppa is not available, so there is no declared location :
+        let resp = test::call_service(&mut app, search(43.0)).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+
+        let peppa = user::User {
+            phone_number_hash: String::from("Peppa"),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: DateTime::from(Utc::now() + Duration::hours(1)),
+            location_precision: user::LocationPrecision::Approximate,
+            status_message: None,
+            contacts_phone_number_hash: vec![],
+        };
+        let req = test::TestRequest::post()
+            .header("authorization", bearer("Peppa"))
+            .uri("/user_available")
+            .set_json(&peppa)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        // Peppa can't search from 10 km away :
+        let resp = test::call_service(&mut app, search(43.09)).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+
+        // Posting the availability counts too :
+        let burst = RateLimitConfiguration::default().nearby_burst;
+        for _ in 3..burst {
+            let resp = test::call_service(&mut app, search(43.0)).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+        }
+        let resp = test::call_service(&mut app, search(43.0)).await;
+        assert_eq!(resp.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        let body: ErrorBody = test::read_body_json(resp).await;
+        assert_eq!(body.code, "TOO_MANY_REQUESTS");
+    }
+
+    #[actix_rt::test]
+    async fn test_invalid_users_are_rejected() {
+        let mut app = init_app!(InMemoryStore, InMemoryStore::new());
+
+        let rebecca = user::User {
+            phone_number_hash: String::from("Rebecca"),
+            latitude: 143.0,
+            longitude: 6.0,
+            available_until: DateTime::parse_from_rfc3339("2021-05-21T21:00:00+00:00")
+                .expect("Can't parse date"),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![String::from("Peppa")],
+        };
+        let req = test::TestRequest::post()
+            .header("authorization", bearer("Rebecca"))
+            .header("content-type", "application/json")
+            .uri("/user_available")
+            .set_json(&rebecca)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let body: ErrorBody = test::read_body_json(resp).await;
+        let fields: Vec<&str> = body.fields.iter().map(|f| f.field.as_str()).collect();
+        assert_eq!(fields, vec!["latitude", "available_until"]);
+    }
+    #[actix_rt::test]
+    async fn test_requests_must_be_authenticated() {
+        let mut app = init_app!(InMemoryStore, InMemoryStore::new());
+        let rebecca = user::User {
+            phone_number_hash: String::from("Rebecca"),
+            latitude: 43.0,
+            longitude: 6.0,
+            available_until: DateTime::from(Utc::now() + Duration::hours(1)),
+            location_precision: user::LocationPrecision::Exact,
+            status_message: None,
+            contacts_phone_number_hash: vec![String::from("Peppa")],
+        };
+
+        // Without token :
+        let req = test::TestRequest::post()
+            .uri("/user_available")
+            .set_json(&rebecca)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+        // With a token signed by someone else :
+        let forged = TokenSigner::new(b"guessed secret", Duration::hours(1)).issue(
+            "Rebecca",
+            "evil-device",
+            Utc::now(),
+        );
+        let req = test::TestRequest::post()
+            .header("authorization", std::format!("Bearer {}", forged))
+            .uri("/user_available")
+            .set_json(&rebecca)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        let body: ErrorBody = test::read_body_json(resp).await;
+        assert_eq!(body.code, "UNAUTHORIZED");
+
+        // Peppa can't move or hide Rebecca :
+        let req = test::TestRequest::post()
+            .header("authorization", bearer("Peppa"))
+            .uri("/user_available")
+            .set_json(&rebecca)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+        let req = test::TestRequest::delete()
+            .header("authorization", bearer("Peppa"))
+            .uri("/user_available/Rebecca")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+
+        // Rebecca's registered device can :
+        let token = test_signer().issue("Rebecca", "rebecca-phone", Utc::now());
+        let req = test::TestRequest::post()
+            .header("authorization", std::format!("Bearer {}", token))
+            .uri("/user_available")
+            .set_json(&rebecca)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+    }
 }
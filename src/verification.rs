@@ -0,0 +1,55 @@
+pub mod sms_sender;
+pub mod verification_codes;
+
+use sha2::{Digest, Sha256};
+
+/**
+ * Hash of a phone number, as computed by the mobile application :
+ * SHA-256 of its E.164 form, in hexadecimal.
+ */
+pub fn phone_number_hash(phone_number: &str) -> String {
+    let digest = Sha256::digest(phone_number.as_bytes());
+    return digest
+        .iter()
+        .map(|byte| std::format!("{:02x}", byte))
+        .collect();
+}
+
+/**
+ * Remove spaces and check the number is in E.164 form (+33612345678).
+ */
+pub fn normalize_phone_number(phone_number: &str) -> Option<String> {
+    let normalized: String = phone_number
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let digits = normalized.strip_prefix('+')?;
+    if digits.len() < 8 || digits.len() > 15 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    return Some(normalized);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phone_number_hash_is_sha256() {
+        assert_eq!(
+            phone_number_hash("+33612345678"),
+            "42d573cfc315801d4cd8eddd5416b416a0bf298b9b9e12d6b07442c91db42bd8"
+        );
+    }
+
+    #[test]
+    fn test_phone_numbers_are_normalized() {
+        assert_eq!(
+            normalize_phone_number("+33 6 12 34 56 78"),
+            Some(String::from("+33612345678"))
+        );
+        assert_eq!(normalize_phone_number("0612345678"), None);
+        assert_eq!(normalize_phone_number("+33 6 12 AB 56 78"), None);
+        assert_eq!(normalize_phone_number("+336"), None);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::error::NearbyError;
+use std::io::Write;
+use std::path::PathBuf;
+
+/**
+ * Something able to send a text message to a phone number.
+ * Real providers are remote services, that's why sending is asynchronous.
+ */
+pub trait SmsSender: Clone + Send + Unpin + 'static {
+    async fn send(&self, phone_number: &str, message: &str) -> Result<(), NearbyError>;
+}
+
+/**
+ * Doesn't send anything : messages are appended to a file (one per line) or
+ * printed when there is no file. Use it for tests and local development.
+ */
+#[derive(Clone)]
+pub struct FileSmsSender {
+    path: Option<PathBuf>,
+}
+
+impl FileSmsSender {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        FileSmsSender { path }
+    }
+
+    /**
+     * Last message sent to this phone number, usefull to read codes in tests.
+     */
+    #[cfg(test)]
+    pub fn last_message_to(&self, phone_number: &str) -> Option<String> {
+        let path = self.path.as_ref()?;
+        let content = std::fs::read_to_string(path).ok()?;
+        let prefix = std::format!("{} ", phone_number);
+        return content
+            .lines()
+            .rev()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .map(String::from);
+    }
+}
+
+impl SmsSender for FileSmsSender {
+    async fn send(&self, phone_number: &str, message: &str) -> Result<(), NearbyError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                println!("SMS to {} : {}", phone_number, message);
+                return Ok(());
+            }
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| NearbyError::Internal(std::format!("Can't write SMS : {}", err)))?;
+        writeln!(file, "{} {}", phone_number, message)
+            .map_err(|err| NearbyError::Internal(std::format!("Can't write SMS : {}", err)))?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::configuration::VerificationConfiguration;
+use crate::error::NearbyError;
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/**
+ * What we know about a phone number during its verification window.
+ */
+struct Verification {
+    /// None once confirmed, expired or after too many wrong attempts.
+    code: Option<String>,
+    expires_at: DateTime<Utc>,
+    /// Wrong codes left, whatever the number of codes sent.
+    remaining_attempts: u32,
+    codes_sent: u32,
+    /// Asking for a new code doesn't give more attempts before this date.
+    window_ends_at: DateTime<Utc>,
+}
+
+/**
+ * One time codes sent to phone numbers, waiting to be confirmed.
+ * They are kept in memory : a code must be confirmed on the instance that sent it.
+ * Attempts and codes are counted per phone number over a window, so asking for
+ * new codes neither gives more guesses nor sends unlimited SMS.
+ */
+#[derive(Clone)]
+pub struct VerificationCodes {
+    verifications: Arc<Mutex<HashMap<String, Verification>>>,
+    validity: Duration,
+    window: Duration,
+    max_attempts: u32,
+    max_codes: u32,
+}
+
+impl VerificationCodes {
+    pub fn new(configuration: &VerificationConfiguration) -> Self {
+        VerificationCodes {
+            verifications: Arc::new(Mutex::new(HashMap::new())),
+            validity: Duration::minutes(configuration.code_validity_minutes),
+            window: Duration::minutes(configuration.window_minutes),
+            max_attempts: configuration.max_attempts,
+            max_codes: configuration.max_codes,
+        }
+    }
+
+    /**
+     * Create a new 6 digits code for this phone number, replacing the previous one.
+     * Return the code and its expiration date, or `TooManyRequests` when this
+     * number had too many codes or wrong attempts during its window.
+     */
+    pub fn create(
+        &self,
+        phone_number: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(String, DateTime<Utc>), NearbyError> {
+        let code = std::format!("{:06}", rand::thread_rng().gen_range(0, 1_000_000));
+        let expires_at = now + self.validity;
+        let mut verifications = self.verifications.lock().map_err(|_| Self::lock_error())?;
+        verifications.retain(|_, verification| verification.window_ends_at > now);
+        let verification =
+            verifications
+                .entry(String::from(phone_number))
+                .or_insert(Verification {
+                    code: None,
+                    expires_at,
+                    remaining_attempts: self.max_attempts,
+                    codes_sent: 0,
+                    window_ends_at: now + self.window,
+                });
+        if verification.codes_sent >= self.max_codes || verification.remaining_attempts == 0 {
+            return Err(NearbyError::TooManyRequests(String::from(
+                "Too many verification codes for this phone number, try again later",
+            )));
+        }
+        verification.code = Some(code.clone());
+        verification.expires_at = expires_at;
+        verification.codes_sent += 1;
+        return Ok((code, expires_at));
+    }
+
+    /**
+     * Check the code sent for this phone number. A code can only be confirmed
+     * once, and is forgotten after too many wrong attempts.
+     */
+    pub fn confirm(
+        &self,
+        phone_number: &str,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), NearbyError> {
+        let refused = || NearbyError::Unauthorized(String::from("Invalid verification code"));
+        let mut verifications = self.verifications.lock().map_err(|_| Self::lock_error())?;
+        let verification = verifications.get_mut(phone_number).ok_or_else(refused)?;
+        let expected = match &verification.code {
+            Some(expected) if verification.expires_at > now => expected,
+            _ => return Err(refused()),
+        };
+        if expected != code {
+            verification.remaining_attempts = verification.remaining_attempts.saturating_sub(1);
+            if verification.remaining_attempts == 0 {
+                verification.code = None;
+            }
+            return Err(refused());
+        }
+        verifications.remove(phone_number);
+        return Ok(());
+    }
+
+    fn lock_error() -> NearbyError {
+        NearbyError::Internal(String::from("Verification codes are poisoned"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_codes(max_attempts: u32, max_codes: u32) -> VerificationCodes {
+        return VerificationCodes::new(&VerificationConfiguration {
+            max_attempts,
+            max_codes,
+            ..VerificationConfiguration::default()
+        });
+    }
+
+    #[test]
+    fn test_codes_can_be_confirmed_once() {
+        let codes = test_codes(3, 3);
+        let now = Utc::now();
+        let (code, _) = codes
+            .create("+33612345678", now)
+            .expect("Can't create code");
+        assert_eq!(code.len(), 6);
+        assert!(codes.confirm("+33612345678", &code, now).is_ok());
+        assert!(codes.confirm("+33612345678", &code, now).is_err());
+    }
+
+    #[test]
+    fn test_expired_codes_are_refused() {
+        let codes = test_codes(3, 3);
+        let now = Utc::now();
+        let (code, _) = codes
+            .create("+33612345678", now)
+            .expect("Can't create code");
+        assert!(codes
+            .confirm("+33612345678", &code, now + Duration::minutes(11))
+            .is_err());
+    }
+
+    #[test]
+    fn test_codes_are_forgotten_after_too_many_attempts() {
+        let codes = test_codes(2, 3);
+        let now = Utc::now();
+        let (code, _) = codes
+            .create("+33612345678", now)
+            .expect("Can't create code");
+        let wrong_code = if code == "000000" { "111111" } else { "000000" };
+        assert!(codes.confirm("+33612345678", wrong_code, now).is_err());
+        assert!(codes.confirm("+33612345678", wrong_code, now).is_err());
+        assert!(codes.confirm("+33612345678", &code, now).is_err());
+    }
+
+    #[test]
+    fn test_new_codes_dont_give_more_attempts() {
+        let codes = test_codes(2, 5);
+        let now = Utc::now();
+        for _ in 0..2 {
+            let (code, _) = codes
+                .create("+33612345678", now)
+                .expect("Can't create code");
+            let wrong_code = if code == "000000" { "111111" } else { "000000" };
+            assert!(codes.confirm("+33612345678", wrong_code, now).is_err());
+        }
+        assert!(std::matches!(
+            codes.create("+33612345678", now),
+            Err(NearbyError::TooManyRequests(_))
+        ));
+
+        // Once the window is over, the number can try again :
+        let later = now + Duration::minutes(61);
+        let (code, _) = codes
+            .create("+33612345678", later)
+            .expect("Can't create code");
+        assert!(codes.confirm("+33612345678", &code, later).is_ok());
+    }
+
+    #[test]
+    fn test_codes_sent_to_a_number_are_limited() {
+        let codes = test_codes(3, 2);
+        let now = Utc::now();
+        assert!(codes.create("+33612345678", now).is_ok());
+        assert!(codes.create("+33612345678", now).is_ok());
+        assert!(std::matches!(
+            codes.create("+33612345678", now),
+            Err(NearbyError::TooManyRequests(_))
+        ));
+        // Others are not limited :
+        assert!(codes.create("+33700000000", now).is_ok());
+    }
+}